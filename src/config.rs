@@ -1,10 +1,281 @@
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Key, or combination of keys that must all be held, activating the Fn layer.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum LayerKey {
+    Single(u16),
+    Combo(Vec<u16>),
+}
+
+impl LayerKey {
+    pub fn codes(&self) -> &[u16] {
+        match self {
+            LayerKey::Single(code) => std::slice::from_ref(code),
+            LayerKey::Combo(codes) => codes,
+        }
+    }
+}
+
+impl Default for LayerKey {
+    fn default() -> Self {
+        LayerKey::Single(crate::core::KEY_SPACE)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Modifier {
+    Shift,
+    Ctrl,
+    Alt,
+    Meta,
+}
+
+/// What to do while the session is locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockBehavior {
+    /// Ungrab the keyboard until the session is unlocked.
+    Pause,
+    /// Keep remapping but stop reporting keys to the UI.
+    PrivacyOnly,
+    #[default]
+    Off,
+}
+
+/// How spacefn-rs uses the keyboard, fixed at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Grab the keyboard and remap it through a virtual keyboard.
+    #[default]
+    Grab,
+    /// Only read the keyboard and report its keys, leaving it to work as usual.
+    Monitor,
+    /// Grab the keyboard but send every event on unchanged, for testing.
+    Passthrough,
+}
+
+impl Mode {
+    pub fn grabs(self) -> bool {
+        self != Mode::Monitor
+    }
+
+    pub fn remaps(self) -> bool {
+        self == Mode::Grab
+    }
+}
+
+/// What loading another config does to keys held in the Fn layer under the current one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SwitchPolicy {
+    /// Release them with the current mappings and switch at once.
+    #[default]
+    Release,
+    /// Keep the current config until the layer is let go, then switch.
+    Defer,
+}
+
+/// When a Fn layer mapping's extended key is pressed relative to its main key.
+/// Releases happen in the opposite order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtOrder {
+    #[default]
+    Before,
+    After,
+}
+
+/// Remap applied outside the Fn layer, only while exactly the `when` modifiers are held.
+/// The Fn layer always wins: base remaps are never consulted in Decide or Shift.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BaseMapping {
+    pub key: u16,
+    pub to: u16,
+    #[serde(default)]
+    pub ext: u16,
+    #[serde(default)]
+    pub when: Vec<Modifier>,
+}
+
+/// Key that types itself when tapped and acts as `hold` when held for `hold_ms`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HoldTap {
+    pub key: u16,
+    pub hold: u16,
+    /// Overrides the decide timeout for this key.
+    #[serde(default)]
+    pub hold_ms: Option<u64>,
+}
+
+/// Something a Fn layer key does instead of emitting a key.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Runs a command through `sh -c` without waiting for it.
+    Exec(String),
+    /// Shifts the letters of the next word, ending at a space, Enter or punctuation.
+    /// Written `caps_word = true`.
+    CapsWord(bool),
+    /// Releases every key spacefn holds, for stuck keys. Written `reset = true`.
+    Reset(bool),
+    /// Presses a key for a set time, for apps that miss short presses.
+    /// Written `timed = { key = 28, hold_ms = 80 }`.
+    Timed(TimedPress),
+}
+
+/// See `Action::Timed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TimedPress {
+    pub key: u16,
+    /// Released this long after the press, however long the source key is held.
+    pub hold_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ActionMapping {
+    pub key: u16,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+/// What a Fn layer mapping does while its source key is held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RepeatMode {
+    /// Tap the target once on press, ignoring repeats.
+    OneShot,
+    /// Hold the target and pass on the keyboard's own repeats.
+    #[default]
+    Held,
+    /// Hold the target and repeat it at auto_repeat_delay_ms / auto_repeat_interval_ms,
+    /// ignoring the keyboard's repeats.
+    AutoRepeat,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RepeatMapping {
+    pub key: u16,
+    pub repeat: RepeatMode,
+}
+
+/// A key sequence typed while holding the layer key, e.g. g then g.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LeaderSequence {
+    pub keys: Vec<u16>,
+    /// Pressed in order and released in reverse, e.g. [29, 46] for Ctrl+C.
+    pub send: Vec<u16>,
+}
+
+/// Another keyboard remapped by its own state machine and virtual device, e.g. a macro pad.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeviceGroup {
+    pub name: String,
+    /// Settings for this keyboard alone, nothing is inherited from the top level.
+    #[serde(flatten)]
+    pub config: Config,
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub keyboard: String,
     pub keys_map: Vec<[u32; 3]>,
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub layer_key: LayerKey,
+    #[serde(default)]
+    pub base_map: Vec<BaseMapping>,
+    #[serde(default)]
+    pub hold_tap: Vec<HoldTap>,
+    #[serde(default)]
+    pub lock_behavior: LockBehavior,
+    /// Keep running with the window hidden when it is closed and no tray icon is available.
+    /// Without a tray the window cannot be shown again; restart spacefn-rs to get it back.
+    /// When false, closing the window in that case stops spacefn-rs.
+    #[serde(default)]
+    pub keep_running_hidden: bool,
+    /// Release held Shift/Ctrl/Alt/Meta while the Fn layer is active and restore them after.
+    #[serde(default)]
+    pub neutralize_modifiers: bool,
+    /// How often to check held keys against the device, 0 disables it.
+    #[serde(default)]
+    pub reconcile_interval_ms: u64,
+    /// The layer key must be held this long before other keys are mapped, 0 disables it.
+    /// Keys pressed sooner are typed as the layer key followed by the key.
+    #[serde(default)]
+    pub min_layer_hold_ms: u64,
+    /// A first key pressed sooner than this after the layer key is typed, not mapped,
+    /// as fast typing overlaps Space with the next key. 0 disables it.
+    #[serde(default)]
+    pub typing_overlap_ms: u64,
+    /// Keys that commit Decide to Shift as soon as they are pressed.
+    #[serde(default)]
+    pub instant_keys: Vec<u16>,
+    /// Mappings for tapping the layer key and then holding it, e.g. Space, Space+J.
+    /// They take precedence over keys_map in that layer. While any are set, a tap of
+    /// the layer key is held back for double_tap_ms to see if it is pressed again.
+    #[serde(default)]
+    pub double_keys_map: Vec<[u32; 3]>,
+    /// Defaults to 200.
+    #[serde(default)]
+    pub double_tap_ms: Option<u64>,
+    /// Whether keys_map extended keys are pressed before or after the main key.
+    #[serde(default)]
+    pub ext_order: ExtOrder,
+    /// Fn layer keys bound to actions, taking precedence over keys_map.
+    #[serde(default)]
+    pub actions: Vec<ActionMapping>,
+    /// Emit mapped modifiers before other keys when flushing keys buffered during Decide.
+    #[serde(default)]
+    pub modifiers_first: bool,
+    /// Output codes treated as modifiers by modifiers_first; empty means Shift/Ctrl/Alt/Meta.
+    #[serde(default)]
+    pub modifier_keys: Vec<u16>,
+    /// Key events to drop right after grabbing the keyboard, 0 disables it.
+    #[serde(default)]
+    pub grab_grace_ms: u64,
+    /// Repeat modes of Fn layer keys, held for any key not listed.
+    #[serde(default)]
+    pub repeat: Vec<RepeatMapping>,
+    /// Defaults to 250.
+    #[serde(default)]
+    pub auto_repeat_delay_ms: Option<u64>,
+    /// Defaults to 33.
+    #[serde(default)]
+    pub auto_repeat_interval_ms: Option<u64>,
+    /// Sequences typed in the Fn layer, taking precedence over keys_map for their keys.
+    #[serde(default)]
+    pub leader: Vec<LeaderSequence>,
+    /// How long to wait for the next key of a sequence, defaults to 1000.
+    #[serde(default)]
+    pub leader_timeout_ms: Option<u64>,
+    /// Name of an existing virtual keyboard to write to instead of creating one.
+    /// One is created as usual if no such device is found.
+    #[serde(default)]
+    pub output_device: String,
+    /// Turn NumLock on while the Fn layer presses keypad digits and off again when it ends,
+    /// see `apply_numpad_preset`.
+    #[serde(default)]
+    pub numpad_numlock: bool,
+    /// How a config loaded through the control socket treats held keys.
+    #[serde(default)]
+    pub switch_while_held: SwitchPolicy,
+    /// Window classes, e.g. "steam_app_730", that pause remapping while focused.
+    /// Read at startup.
+    #[serde(default)]
+    pub pause_for_apps: Vec<String>,
+    /// Prints the focused window's class for pause_for_apps. Defaults to asking X11 with
+    /// xprop; e.g. `hyprctl activewindow -j | jq -r .class` on Hyprland.
+    #[serde(default)]
+    pub focus_command: String,
+    /// Further keyboards, each with its own config. Only top-level groups are used.
+    #[serde(default)]
+    pub groups: Vec<DeviceGroup>,
 }
 
 impl Default for Config {
@@ -12,25 +283,309 @@ impl Default for Config {
         Self {
             keyboard: String::new(),
             keys_map: Vec::new(),
+            mode: Mode::Grab,
+            layer_key: LayerKey::default(),
+            base_map: Vec::new(),
+            hold_tap: Vec::new(),
+            neutralize_modifiers: false,
+            reconcile_interval_ms: 0,
+            lock_behavior: LockBehavior::Off,
+            keep_running_hidden: false,
+            min_layer_hold_ms: 0,
+            typing_overlap_ms: 0,
+            instant_keys: Vec::new(),
+            double_keys_map: Vec::new(),
+            double_tap_ms: None,
+            ext_order: ExtOrder::Before,
+            actions: Vec::new(),
+            modifiers_first: false,
+            grab_grace_ms: 0,
+            modifier_keys: Vec::new(),
+            repeat: Vec::new(),
+            auto_repeat_delay_ms: None,
+            auto_repeat_interval_ms: None,
+            leader: Vec::new(),
+            leader_timeout_ms: None,
+            output_device: String::new(),
+            numpad_numlock: false,
+            switch_while_held: SwitchPolicy::Release,
+            pause_for_apps: Vec::new(),
+            focus_command: String::new(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+/// Fn layer mappings of the numpad preset, see `Config::apply_numpad_preset`.
+#[cfg(any(feature = "ui", test))]
+pub const NUMPAD_PRESET: [[u32; 3]; 10] = [
+    [22, 71, 0],
+    [23, 72, 0],
+    [24, 73, 0],
+    [36, 75, 0],
+    [37, 76, 0],
+    [38, 77, 0],
+    [50, 79, 0],
+    [51, 80, 0],
+    [52, 81, 0],
+    [49, 82, 0],
+];
+
+/// Per-machine settings merged over the config, from e.g. `config.$HOSTNAME.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigOverlay {
+    pub keyboard: Option<String>,
+    /// Replace the config's mappings with the same source key, others are added.
+    #[serde(default)]
+    pub keys_map: Vec<[u32; 3]>,
+}
+
+/// Window preferences, stored apart from the remapping config.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UiSettings {
+    pub scale: f32,
+    pub min_window_size: [f32; 2],
+    /// Device last picked in the UI, used when the config names none.
+    pub last_device: String,
+    /// Show each recent key's event type, code and value as evtest prints them.
+    pub raw_values: bool,
+}
+
+impl Default for UiSettings {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            min_window_size: [500.0, 400.0],
+            last_device: String::new(),
+            raw_values: false,
+        }
+    }
+}
+
+impl UiSettings {
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+        if !path.exists() {
+            return Self::default();
+        }
+        match std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(toml::from_str(&content)?))
+        {
+            Ok(settings) => settings,
+            Err(e) => {
+                log::warn!("Failed to load UI settings from {:?}: {}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    #[cfg(feature = "ui")]
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow::anyhow!("No home directory found"))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| io_error("creating config directory", parent, e))?;
         }
+        std::fs::write(&path, toml::to_string_pretty(self)?)
+            .map_err(|e| io_error("writing UI settings", &path, e))?;
+        Ok(())
+    }
+
+    fn path() -> Option<PathBuf> {
+        dirs::home_dir().map(|home| home.join(".config/spacefn/ui.toml"))
     }
 }
 
 impl Config {
+    /// Loads the config with this machine's overlays merged over it, see `apply_overlays`.
     pub fn load() -> anyhow::Result<Self> {
-        let config_paths = Self::config_paths();
+        let mut config = Self::load_base()?;
+        config.apply_overlays()?;
+        for warning in config.validate() {
+            log::warn!("{}", warning);
+        }
+        Ok(config)
+    }
 
-        for path in config_paths {
-            if path.exists() {
-                let content = std::fs::read_to_string(&path)?;
-                let config: Config = toml::from_str(&content)?;
+    /// Loads the config file as written, without overlays, for editing and saving it.
+    pub fn load_base() -> anyhow::Result<Self> {
+        match Self::path_in_use() {
+            Some(path) => {
+                let config = Self::load_from(&path)?;
                 log::info!("Loaded config from {:?}", path);
-                return Ok(config);
+                Ok(config)
+            }
+            None => {
+                log::warn!("No config file found, using default config");
+                Ok(Config::default())
             }
         }
+    }
+
+    /// Merges the overlays next to the config file in use, e.g. `config.$HOSTNAME.toml`.
+    pub fn apply_overlays(&mut self) -> anyhow::Result<()> {
+        let Some(path) = Self::path_in_use() else {
+            return Ok(());
+        };
+        for overlay_path in overlay_paths(&path, &environment_names()) {
+            if overlay_path.exists() {
+                let content = std::fs::read_to_string(&overlay_path)
+                    .map_err(|e| io_error("reading config overlay", &overlay_path, e))?;
+                let overlay = toml::from_str(&content).with_context(|| {
+                    format!("Invalid config overlay {}", overlay_path.display())
+                })?;
+                self.merge(overlay);
+                log::info!("Applied config overlay {:?}", overlay_path);
+            }
+        }
+        Ok(())
+    }
 
-        log::warn!("No config file found, using default config");
-        Ok(Config::default())
+    fn path_in_use() -> Option<PathBuf> {
+        Self::config_paths().into_iter().find(|path| path.exists())
+    }
+
+    pub fn load_from(path: &Path) -> anyhow::Result<Self> {
+        if path.is_dir() {
+            anyhow::bail!(
+                "Config path {} is a directory, expected a file",
+                path.display()
+            );
+        }
+        let content =
+            std::fs::read_to_string(path).map_err(|e| io_error("reading config", path, e))?;
+        toml::from_str(&content).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// Applies `overlay` on top of this config.
+    pub fn merge(&mut self, overlay: ConfigOverlay) {
+        if let Some(keyboard) = overlay.keyboard {
+            self.keyboard = keyboard;
+        }
+        for mapping in overlay.keys_map {
+            match self.keys_map.iter_mut().find(|m| m[0] == mapping[0]) {
+                Some(existing) => *existing = mapping,
+                None => self.keys_map.push(mapping),
+            }
+        }
+    }
+
+    /// Maps Space+U I O, J K L, M , . to keypad 7 8 9, 4 5 6, 1 2 3 and Space+N to keypad 0,
+    /// replacing mappings of those keys, and turns on numpad_numlock.
+    #[cfg(any(feature = "ui", test))]
+    pub fn apply_numpad_preset(&mut self) {
+        self.merge(ConfigOverlay {
+            keyboard: None,
+            keys_map: NUMPAD_PRESET.to_vec(),
+        });
+        self.numpad_numlock = true;
+    }
+
+    /// Checks the config for suspicious but non-fatal settings.
+    pub fn validate(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.layer_key.codes().is_empty() {
+            warnings.push("layer_key is empty, the Fn layer can never activate".to_string());
+        }
+
+        for &code in &self.instant_keys {
+            if !self.keys_map.iter().any(|m| m[0] == u32::from(code)) {
+                warnings.push(format!("Instant key {} has no mapping", code));
+            }
+        }
+
+        for mapping in &self.keys_map {
+            if mapping.iter().any(|&c| c > u32::from(crate::core::KEY_MAX)) {
+                warnings.push(format!(
+                    "Mapping {:?} uses a code above {}, the highest key code",
+                    mapping,
+                    crate::core::KEY_MAX
+                ));
+            }
+        }
+
+        for mapping in &self.actions {
+            match &mapping.action {
+                Action::Exec(command) if command.trim().is_empty() => {
+                    warnings.push(format!("Exec action on key {} has no command", mapping.key));
+                }
+                Action::CapsWord(false) | Action::Reset(false) => {
+                    warnings.push(format!("Action on key {} is disabled", mapping.key));
+                }
+                Action::Timed(timed) if timed.hold_ms == 0 => {
+                    warnings.push(format!(
+                        "Timed action on key {} has hold_ms 0, it is a plain tap",
+                        mapping.key
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        for sequence in &self.leader {
+            if sequence.keys.is_empty() {
+                warnings.push("Leader sequence with no keys is ignored".to_string());
+            }
+            if sequence
+                .keys
+                .iter()
+                .any(|&code| self.layer_key.codes().contains(&code))
+            {
+                warnings.push(format!(
+                    "Leader sequence {:?} contains the layer key and can never match",
+                    sequence.keys
+                ));
+            }
+        }
+
+        for (i, group) in self.groups.iter().enumerate() {
+            if group.config.keyboard.is_empty() {
+                warnings.push(format!(
+                    "Group {:?} has no keyboard and is skipped",
+                    group.name
+                ));
+            } else if group.config.keyboard == self.keyboard
+                || self.groups[..i]
+                    .iter()
+                    .any(|g| g.config.keyboard == group.config.keyboard)
+            {
+                warnings.push(format!(
+                    "Group {:?} uses keyboard {} which is already in use",
+                    group.name, group.config.keyboard
+                ));
+            }
+            if self.groups[..i].iter().any(|g| g.name == group.name) {
+                warnings.push(format!(
+                    "Group name {:?} is used more than once",
+                    group.name
+                ));
+            }
+            if !group.config.groups.is_empty() {
+                warnings.push(format!(
+                    "Groups nested in group {:?} are ignored",
+                    group.name
+                ));
+            }
+            for warning in group.config.validate() {
+                warnings.push(format!("Group {:?}: {}", group.name, warning));
+            }
+        }
+
+        warnings
+    }
+
+    /// The config of the group called `name`.
+    pub fn group(&self, name: &str) -> Option<&Config> {
+        self.groups
+            .iter()
+            .find(|g| g.name == name)
+            .map(|g| &g.config)
     }
 
     fn config_paths() -> Vec<PathBuf> {
@@ -51,13 +606,335 @@ impl Config {
         paths
     }
 
+    pub fn to_toml_string(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
     pub fn save(&self, path: &PathBuf) -> anyhow::Result<()> {
         if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
+            std::fs::create_dir_all(parent)
+                .map_err(|e| io_error("creating config directory", parent, e))?;
         }
-        let content = toml::to_string_pretty(self)?;
-        std::fs::write(path, content)?;
+        let content = self.to_toml_string()?;
+        std::fs::write(path, content).map_err(|e| io_error("writing config", path, e))?;
         log::info!("Saved config to {:?}", path);
         Ok(())
     }
 }
+
+/// Names overlays are picked by, least specific first: the desktop session, then the hostname.
+fn environment_names() -> Vec<String> {
+    let mut names = Vec::new();
+    if let Ok(desktop) = std::env::var("XDG_SESSION_DESKTOP") {
+        if !desktop.is_empty() {
+            names.push(desktop.to_lowercase());
+        }
+    }
+    match nix::unistd::gethostname() {
+        Ok(hostname) => names.push(hostname.to_string_lossy().into_owned()),
+        Err(e) => log::debug!("Failed to get the hostname: {}", e),
+    }
+    names
+}
+
+/// Overlays for `base`, e.g. `config.laptop.toml` for `config.toml`, in the order of `names`.
+fn overlay_paths(base: &Path, names: &[String]) -> Vec<PathBuf> {
+    let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = base.extension().unwrap_or_default().to_string_lossy();
+    names
+        .iter()
+        .map(|name| base.with_file_name(format!("{}.{}.{}", stem, name, extension)))
+        .collect()
+}
+
+/// Describes a failed file operation on `path` in terms the user can act on.
+fn io_error(action: &str, path: &Path, e: std::io::Error) -> anyhow::Error {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied => anyhow::anyhow!(
+            "Permission denied {} {}, check its owner and mode",
+            action,
+            path.display()
+        ),
+        std::io::ErrorKind::NotFound => {
+            anyhow::anyhow!("Failed {} {}: it does not exist", action, path.display())
+        }
+        _ => anyhow::anyhow!("Failed {} {}: {}", action, path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("spacefn-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_load_from_directory() {
+        let dir = scratch_path("config-dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        let err = Config::load_from(&dir).unwrap_err().to_string();
+        std::fs::remove_dir(&dir).unwrap();
+        assert!(err.contains("is a directory"), "{}", err);
+        assert!(err.contains(&dir.display().to_string()), "{}", err);
+    }
+
+    #[test]
+    fn test_io_error_permission_denied() {
+        let path = Path::new("/etc/spacefn/config.toml");
+        let e = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let err = io_error("reading config", path, e).to_string();
+        assert_eq!(
+            err,
+            "Permission denied reading config /etc/spacefn/config.toml, check its owner and mode"
+        );
+    }
+
+    #[test]
+    fn test_save_parent_not_creatable() {
+        // The parent's parent is a file, so the directory cannot be created.
+        let file = scratch_path("not-a-dir");
+        std::fs::write(&file, "").unwrap();
+        let target = file.join("spacefn/config.toml");
+        let err = Config::default().save(&target).unwrap_err().to_string();
+        std::fs::remove_file(&file).unwrap();
+        assert!(err.contains("creating config directory"), "{}", err);
+        assert!(err.contains(&file.display().to_string()), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_instant_key_without_mapping() {
+        let config = Config {
+            keys_map: vec![[36, 108, 0]],
+            instant_keys: vec![36, 37],
+            ..Default::default()
+        };
+
+        let warnings = config.validate();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("37"));
+    }
+
+    #[test]
+    fn test_validate_code_above_key_max() {
+        let config = Config {
+            keys_map: vec![[36, 0x110, 0], [37, 0x300, 0]],
+            ..Default::default()
+        };
+
+        let warnings = config.validate();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("768"));
+    }
+
+    #[test]
+    fn test_actions_parse() {
+        let content = r#"
+keyboard = ""
+keys_map = []
+
+[[actions]]
+key = 28
+exec = "alacritty"
+"#;
+        let config: Config = toml::from_str(content).unwrap();
+        assert_eq!(config.actions.len(), 1);
+        assert_eq!(config.actions[0].key, 28);
+        assert_eq!(
+            config.actions[0].action,
+            Action::Exec("alacritty".to_string())
+        );
+
+        let round_trip: Config = toml::from_str(&config.to_toml_string().unwrap()).unwrap();
+        assert_eq!(round_trip.actions[0].action, config.actions[0].action);
+
+        let config: Config = toml::from_str(
+            "keyboard = \"\"\nkeys_map = []\n[[actions]]\nkey = 58\ncaps_word = true\n",
+        )
+        .unwrap();
+        assert_eq!(config.actions[0].action, Action::CapsWord(true));
+
+        let config: Config = toml::from_str(
+            "keyboard = \"\"\nkeys_map = []\n[[actions]]\nkey = 36\ntimed = { key = 28, hold_ms = 80 }\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.actions[0].action,
+            Action::Timed(TimedPress {
+                key: 28,
+                hold_ms: 80
+            })
+        );
+    }
+
+    #[test]
+    fn test_groups_parse() {
+        let content = r#"
+keyboard = "/dev/input/event3"
+keys_map = [[36, 105, 0]]
+
+[[groups]]
+name = "macropad"
+keyboard = "/dev/input/event7"
+keys_map = [[2, 59, 0]]
+layer_key = 11
+
+[[groups]]
+name = "numpad"
+keyboard = "/dev/input/event3"
+keys_map = []
+"#;
+        let config: Config = toml::from_str(content).unwrap();
+        assert_eq!(config.groups.len(), 2);
+        let macropad = config.group("macropad").unwrap();
+        assert_eq!(macropad.keyboard, "/dev/input/event7");
+        assert_eq!(macropad.keys_map, vec![[2, 59, 0]]);
+        assert_eq!(macropad.layer_key, LayerKey::Single(11));
+        // Nothing is inherited from the top level.
+        assert!(macropad.groups.is_empty());
+        assert!(config.group("missing").is_none());
+
+        let warnings = config.validate();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("numpad") && warnings[0].contains("already in use"));
+
+        let round_trip: Config = toml::from_str(&config.to_toml_string().unwrap()).unwrap();
+        assert_eq!(round_trip.groups[0].config.keys_map, macropad.keys_map);
+    }
+
+    #[test]
+    fn test_overlay_merge() {
+        let mut config = Config {
+            keyboard: "/dev/input/event3".to_string(),
+            keys_map: vec![[36, 105, 0], [37, 108, 0]],
+            ..Default::default()
+        };
+        let overlay: ConfigOverlay = toml::from_str(
+            "keyboard = \"/dev/input/by-id/laptop-kbd\"\nkeys_map = [[37, 103, 0], [38, 106, 0]]\n",
+        )
+        .unwrap();
+        config.merge(overlay);
+        assert_eq!(config.keyboard, "/dev/input/by-id/laptop-kbd");
+        assert_eq!(
+            config.keys_map,
+            vec![[36, 105, 0], [37, 103, 0], [38, 106, 0]]
+        );
+
+        // An overlay without a keyboard keeps the base one.
+        config.merge(toml::from_str("keys_map = [[36, 0, 0]]\n").unwrap());
+        assert_eq!(config.keyboard, "/dev/input/by-id/laptop-kbd");
+        assert_eq!(config.keys_map[0], [36, 0, 0]);
+
+        // Anything else is a mistake, not silently ignored.
+        assert!(toml::from_str::<ConfigOverlay>("layer_key = 58\n").is_err());
+    }
+
+    #[test]
+    fn test_overlay_paths() {
+        let paths = overlay_paths(
+            Path::new("/home/me/.config/spacefn/config.toml"),
+            &["gnome".to_string(), "laptop".to_string()],
+        );
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/home/me/.config/spacefn/config.gnome.toml"),
+                PathBuf::from("/home/me/.config/spacefn/config.laptop.toml"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_base_map_parse() {
+        let content = r#"
+keyboard = ""
+keys_map = []
+
+[[base_map]]
+key = 2
+to = 2
+ext = 42
+
+[[base_map]]
+key = 3
+to = 4
+when = ["shift"]
+"#;
+        let config: Config = toml::from_str(content).unwrap();
+        assert_eq!(config.base_map.len(), 2);
+        assert!(config.base_map[0].when.is_empty());
+        assert_eq!(config.base_map[1].when, vec![Modifier::Shift]);
+    }
+
+    #[test]
+    fn test_lock_behavior_parse() {
+        let config: Config = toml::from_str("keyboard = \"\"\nkeys_map = []\n").unwrap();
+        assert_eq!(config.lock_behavior, LockBehavior::Off);
+
+        let config: Config =
+            toml::from_str("keyboard = \"\"\nkeys_map = []\nlock_behavior = \"privacy-only\"\n")
+                .unwrap();
+        assert_eq!(config.lock_behavior, LockBehavior::PrivacyOnly);
+    }
+
+    #[test]
+    fn test_to_toml_string_round_trip() {
+        let config = Config {
+            keyboard: "/dev/input/event3".to_string(),
+            keys_map: vec![[36, 105, 0], [104, 0, 109]],
+            layer_key: LayerKey::Combo(vec![56, 100]),
+            base_map: vec![BaseMapping {
+                key: 2,
+                to: 2,
+                ext: 42,
+                when: Vec::new(),
+            }],
+            ..Default::default()
+        };
+
+        let content = config.to_toml_string().unwrap();
+        let parsed: Config = toml::from_str(&content).unwrap();
+        assert_eq!(parsed.keyboard, config.keyboard);
+        assert_eq!(parsed.keys_map, config.keys_map);
+        assert_eq!(parsed.layer_key, config.layer_key);
+        assert_eq!(parsed.base_map, config.base_map);
+    }
+
+    #[test]
+    fn test_ui_settings_defaults() {
+        let settings: UiSettings = toml::from_str("scale = 2.0\n").unwrap();
+        assert_eq!(settings.scale, 2.0);
+        assert_eq!(settings.min_window_size, [500.0, 400.0]);
+        assert!(settings.last_device.is_empty());
+        assert!(!settings.raw_values);
+    }
+
+    #[test]
+    fn test_mode() {
+        let config: Config = toml::from_str("keyboard = \"\"\nkeys_map = []\n").unwrap();
+        assert_eq!(config.mode, Mode::Grab);
+        assert!(config.mode.grabs() && config.mode.remaps());
+
+        let config: Config =
+            toml::from_str("keyboard = \"\"\nkeys_map = []\nmode = \"monitor\"\n").unwrap();
+        assert_eq!(config.mode, Mode::Monitor);
+        assert!(!config.mode.grabs() && !config.mode.remaps());
+
+        let config: Config =
+            toml::from_str("keyboard = \"\"\nkeys_map = []\nmode = \"passthrough\"\n").unwrap();
+        assert_eq!(config.mode, Mode::Passthrough);
+        assert!(config.mode.grabs() && !config.mode.remaps());
+    }
+
+    #[test]
+    fn test_layer_key_single_or_combo() {
+        let config: Config = toml::from_str("keyboard = \"\"\nkeys_map = []\n").unwrap();
+        assert_eq!(config.layer_key.codes(), &[57]);
+
+        let config: Config =
+            toml::from_str("keyboard = \"\"\nkeys_map = []\nlayer_key = [56, 100]\n").unwrap();
+        assert_eq!(config.layer_key, LayerKey::Combo(vec![56, 100]));
+        assert_eq!(config.layer_key.codes(), &[56, 100]);
+    }
+}
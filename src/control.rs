@@ -0,0 +1,218 @@
+use crate::CoreCommand;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// How long a client waits for the core to answer.
+const REPLY_TIMEOUT_MS: u64 = 2000;
+
+/// Where the core sends the outcome of a socket request, with any text to add to `ok`.
+pub type Reply = mpsc::Sender<Result<String, String>>;
+
+/// A request read from the control socket, one per line.
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    Reload,
+    LoadConfig(PathBuf),
+    Revert,
+    Reset,
+    /// Switches the mapping of a source key on or off.
+    SetMappingEnabled(u16, bool),
+}
+
+pub fn parse_request(line: &str) -> Result<Request, String> {
+    let line = line.trim();
+    let (name, arg) = match line.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (line, ""),
+    };
+    match (name, arg) {
+        ("reload", "") => Ok(Request::Reload),
+        ("revert", "") => Ok(Request::Revert),
+        ("reset", "") => Ok(Request::Reset),
+        ("load-config", "") => Err("load-config needs a path".to_string()),
+        ("load-config", path) => Ok(Request::LoadConfig(PathBuf::from(path))),
+        ("enable-mapping" | "disable-mapping", source) => {
+            let source = source
+                .parse()
+                .map_err(|_| format!("{} needs a key code, got {:?}", name, source))?;
+            Ok(Request::SetMappingEnabled(source, name == "enable-mapping"))
+        }
+        ("reload" | "revert" | "reset", _) => Err(format!("{} takes no arguments", name)),
+        _ => Err(format!("Unknown command {:?}", name)),
+    }
+}
+
+/// Turns a request into the command for the core, and the receiver for its reply if it has one.
+pub fn to_command(
+    request: Request,
+) -> (CoreCommand, Option<mpsc::Receiver<Result<String, String>>>) {
+    let (reply, rx) = mpsc::channel();
+    match request {
+        Request::Reload => (CoreCommand::ReloadConfig, None),
+        Request::Reset => (CoreCommand::Reset, None),
+        Request::LoadConfig(path) => (CoreCommand::LoadConfig(path, reply), Some(rx)),
+        Request::Revert => (CoreCommand::RevertConfig(reply), Some(rx)),
+        Request::SetMappingEnabled(source, enabled) => (
+            CoreCommand::SetMappingEnabled(source, enabled, reply),
+            Some(rx),
+        ),
+    }
+}
+
+pub fn socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("spacefn.sock")
+}
+
+/// Serves the control socket on a background thread; failing to bind is not fatal.
+pub fn spawn_control_socket(cmd_tx: mpsc::Sender<CoreCommand>) {
+    let path = socket_path();
+    if !remove_stale_socket(&path) {
+        log::warn!(
+            "Another spacefn is serving {:?}, not starting the control socket",
+            path
+        );
+        return;
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::warn!("Failed to create control socket {:?}: {}", path, e);
+            return;
+        }
+    };
+    if let Err(e) = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)) {
+        log::warn!("Failed to restrict control socket {:?}: {}", path, e);
+    }
+    log::info!("Listening for commands on {:?}", path);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    // A client that keeps its connection open must not hold up the others.
+                    let cmd_tx = cmd_tx.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = serve_client(stream, &cmd_tx) {
+                            log::debug!("Control client error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => log::warn!("Control socket accept failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Removes a socket file left behind by an earlier run, which would make bind fail.
+/// Returns false, leaving the file alone, if something still answers on it.
+fn remove_stale_socket(path: &Path) -> bool {
+    if UnixStream::connect(path).is_ok() {
+        return false;
+    }
+    let _ = std::fs::remove_file(path);
+    true
+}
+
+fn serve_client(stream: UnixStream, cmd_tx: &mpsc::Sender<CoreCommand>) -> anyhow::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = parse_request(&line).and_then(|request| {
+            let (command, reply_rx) = to_command(request);
+            cmd_tx
+                .send(command)
+                .map_err(|_| "spacefn is shutting down".to_string())?;
+            match reply_rx {
+                Some(rx) => rx
+                    .recv_timeout(Duration::from_millis(REPLY_TIMEOUT_MS))
+                    .map_err(|_| "No reply from spacefn".to_string())?,
+                None => Ok(String::new()),
+            }
+        });
+        match result {
+            Ok(text) if text.is_empty() => writeln!(writer, "ok")?,
+            Ok(text) => writeln!(writer, "ok {}", text)?,
+            Err(e) => writeln!(writer, "error {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(parse_request("reload"), Ok(Request::Reload));
+        assert_eq!(parse_request(" revert \n"), Ok(Request::Revert));
+        assert_eq!(
+            parse_request("load-config /home/me/gaming.toml"),
+            Ok(Request::LoadConfig(PathBuf::from("/home/me/gaming.toml")))
+        );
+        assert_eq!(
+            parse_request("disable-mapping 36"),
+            Ok(Request::SetMappingEnabled(36, false))
+        );
+        assert_eq!(
+            parse_request("enable-mapping 36"),
+            Ok(Request::SetMappingEnabled(36, true))
+        );
+        assert!(parse_request("enable-mapping").is_err());
+        assert!(parse_request("disable-mapping j").is_err());
+        assert!(parse_request("load-config").is_err());
+        assert!(parse_request("revert now").is_err());
+        assert_eq!(parse_request("reset"), Ok(Request::Reset));
+        assert!(parse_request("explode").is_err());
+    }
+
+    #[test]
+    fn test_load_config_waits_for_reply() {
+        let (command, rx) = to_command(Request::LoadConfig(PathBuf::from("/tmp/a.toml")));
+        let CoreCommand::LoadConfig(path, reply) = command else {
+            panic!("expected LoadConfig, got {:?}", command);
+        };
+        assert_eq!(path, PathBuf::from("/tmp/a.toml"));
+        reply.send(Err("bad".to_string())).unwrap();
+        assert_eq!(rx.unwrap().recv().unwrap(), Err("bad".to_string()));
+
+        let (command, rx) = to_command(Request::SetMappingEnabled(36, false));
+        let CoreCommand::SetMappingEnabled(36, false, reply) = command else {
+            panic!("expected SetMappingEnabled, got {:?}", command);
+        };
+        reply.send(Ok("36 disabled".to_string())).unwrap();
+        assert_eq!(rx.unwrap().recv().unwrap(), Ok("36 disabled".to_string()));
+
+        let (command, rx) = to_command(Request::Reload);
+        assert!(matches!(command, CoreCommand::ReloadConfig));
+        assert!(rx.is_none());
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let path =
+            std::env::temp_dir().join(format!("spacefn-test-{}-control.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(remove_stale_socket(&path));
+
+        // A running instance keeps its socket.
+        let listener = UnixListener::bind(&path).unwrap();
+        assert!(!remove_stale_socket(&path));
+        assert!(path.exists());
+
+        // Once it is gone the file is stale.
+        drop(listener);
+        assert!(remove_stale_socket(&path));
+        assert!(!path.exists());
+    }
+}
@@ -1,8 +1,99 @@
-use evdev::{AttributeSet, Device, EventType, InputEvent, Key};
+use crate::config::{Action, ExtOrder, Modifier, RepeatMode, SwitchPolicy, TimedPress};
+use crate::leader::{LeaderTrie, Match};
+use evdev::raw_stream::RawDevice;
+use evdev::{AttributeSet, BusType, EventType, InputEvent, KeyCode, SynchronizationCode};
+use std::collections::HashMap;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 const MAX_BUFFER: usize = 8;
 
+pub const KEY_SPACE: u16 = 57;
+pub const KEY_LEFTSHIFT: u16 = 42;
+pub const KEY_NUMLOCK: u16 = 69;
+pub const DECIDE_TIMEOUT_MS: u64 = 200;
+pub const LEADER_TIMEOUT_MS: u64 = 1000;
+pub const AUTO_REPEAT_DELAY_MS: u64 = 250;
+pub const AUTO_REPEAT_INTERVAL_MS: u64 = 33;
+/// Name of the output device, never to be read from.
+pub const VIRTUAL_DEVICE_NAME: &str = "spacefn virtual keyboard";
+/// Highest key code the kernel accepts, KEY_MAX in input-event-codes.h.
+pub const KEY_MAX: u16 = 0x2ff;
+
+pub fn modifier_of(code: u16) -> Option<Modifier> {
+    match code {
+        42 | 54 => Some(Modifier::Shift),
+        29 | 97 => Some(Modifier::Ctrl),
+        56 | 100 => Some(Modifier::Alt),
+        125 | 126 => Some(Modifier::Meta),
+        _ => None,
+    }
+}
+
+/// Whether `code` is a letter key, Q to P, A to L or Z to M.
+fn is_letter(code: u16) -> bool {
+    matches!(code, 16..=25 | 30..=38 | 44..=50)
+}
+
+/// Whether `code` is a keypad key that types a digit or dot only with NumLock on.
+fn needs_numlock(code: u16) -> bool {
+    matches!(code, 71..=73 | 75..=77 | 79..=83)
+}
+
+/// Keys that keep caps word going without being shifted: digits, Backspace, minus.
+fn continues_caps_word(code: u16) -> bool {
+    matches!(code, 2..=12 | 14)
+}
+
+#[cfg(any(feature = "ui", test))]
+/// Physical keyboard row of a key on a standard layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Row {
+    Number,
+    Top,
+    Home,
+    Bottom,
+    Other,
+}
+
+#[cfg(feature = "ui")]
+impl Row {
+    pub const ALL: [Row; 5] = [Row::Number, Row::Top, Row::Home, Row::Bottom, Row::Other];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Row::Number => "Number row",
+            Row::Top => "Top row",
+            Row::Home => "Home row",
+            Row::Bottom => "Bottom row",
+            Row::Other => "Other keys",
+        }
+    }
+}
+
+#[cfg(any(feature = "ui", test))]
+pub fn key_row(code: u16) -> Row {
+    match code {
+        2..=14 | 41 => Row::Number,
+        15..=27 | 43 => Row::Top,
+        28 | 30..=40 | 58 => Row::Home,
+        42 | 44..=54 => Row::Bottom,
+        _ => Row::Other,
+    }
+}
+
+/// Describes a Fn layer mapping for the trace log, e.g.
+/// `KEY_J (36) -> KEY_LEFT (105) + KEY_LEFTCTRL (29)`.
+fn describe_mapping(source: u16, mapped: u16, ext: Option<u16>) -> String {
+    let name = |code: u16| format!("{:?} ({})", KeyCode::new(code), code);
+    let mut text = format!("{} -> {}", name(source), name(mapped));
+    if let Some(ext) = ext {
+        text.push_str(&format!(" + {}", name(ext)));
+    }
+    text
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KeyValue {
     Release = 0,
@@ -10,13 +101,14 @@ pub enum KeyValue {
     Repeat = 2,
 }
 
-impl From<i32> for KeyValue {
-    fn from(v: i32) -> Self {
-        match v {
-            0 => KeyValue::Release,
-            1 => KeyValue::Press,
-            2 => KeyValue::Repeat,
-            _ => KeyValue::Release,
+impl KeyValue {
+    /// Reads an EV_KEY value; devices may send others, which have no meaning here.
+    pub fn parse(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(KeyValue::Release),
+            1 => Some(KeyValue::Press),
+            2 => Some(KeyValue::Repeat),
+            _ => None,
         }
     }
 }
@@ -83,19 +175,268 @@ impl Default for KeyBuffer {
     }
 }
 
+/// Destination for the events produced by the state machine.
+pub trait KeySink {
+    fn send_key(&mut self, code: u16, value: i32) -> anyhow::Result<()>;
+    fn forward_event(&mut self, event: &InputEvent) -> anyhow::Result<()>;
+
+    /// Starts a command for an Exec action without blocking the input thread.
+    fn exec(&mut self, command: &str) -> anyhow::Result<()> {
+        spawn_command(command);
+        Ok(())
+    }
+}
+
+impl KeySink for evdev::uinput::VirtualDevice {
+    fn send_key(&mut self, code: u16, value: i32) -> anyhow::Result<()> {
+        send_key(self, code, value)
+    }
+
+    fn forward_event(&mut self, event: &InputEvent) -> anyhow::Result<()> {
+        forward_event(self, event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub syn_dropped: u64,
+    pub reconciled: u64,
+}
+
+/// A hold-tap key waiting to be resolved, with the events that arrived meanwhile.
+struct PendingHold {
+    code: u16,
+    hold: u16,
+    deadline: Instant,
+    queued: Vec<(u16, i32, Instant)>,
+}
+
 pub struct StateMachine {
     state: State,
     buffer: KeyBuffer,
     pub config: crate::config::Config,
+    decide_deadline: Option<Instant>,
+    /// Until then a key press in Decide is typing, see min_layer_hold_ms.
+    layer_armed_at: Option<Instant>,
+    /// Until then the first key pressed in Decide is typing, see typing_overlap_ms.
+    overlap_until: Option<Instant>,
+    layer_down: Vec<u16>,
+    /// Members of a layer combo let through while waiting for the rest, see `flush_combo`.
+    combo_sent: Vec<u16>,
+    /// When members of a layer combo held back waiting for the rest are let through.
+    combo_deadline: Option<Instant>,
+    /// A tap of the layer key held back to see if the layer key follows, see double_keys_map.
+    pending_tap: Option<Instant>,
+    /// The layer was entered by tapping and then holding the layer key.
+    double: bool,
+    held: Vec<u16>,
+    /// Base remaps currently pressed, as (source, target, extended).
+    base_active: Vec<(u16, u16, Option<u16>)>,
+    /// Real modifiers released on entering Shift, restored when it ends.
+    neutralized: Vec<u16>,
+    pending_hold: Option<PendingHold>,
+    /// Hold-tap keys resolved as held, as (source, hold).
+    held_taps: Vec<(u16, u16)>,
+    leader: LeaderTrie,
+    /// Keys typed so far of a leader sequence.
+    leader_keys: Vec<u16>,
+    leader_deadline: Option<Instant>,
+    /// Keys consumed by a leader sequence whose release must not reach the output.
+    swallowed: Vec<u16>,
+    /// Key events before this are dropped, see grab_grace_ms.
+    grace_until: Option<Instant>,
+    /// Configs replaced by `load_config`, most recent last.
+    previous_configs: Vec<crate::config::Config>,
+    /// A config loaded while keys were held, waiting for Idle, see SwitchPolicy::Defer.
+    /// The flag tells whether `revert_config` should come back to the one it replaces.
+    deferred: Option<(crate::config::Config, bool)>,
+    /// Source keys whose mappings are switched off at runtime, see `set_mapping_enabled`.
+    disabled: Vec<u16>,
+    /// Source key being repeated in software and when its next repeat is due.
+    auto_repeat: Option<(u16, Instant)>,
+    /// Caps word is armed, see `Action::CapsWord`.
+    caps_word: bool,
+    /// Shift is pressed on behalf of caps word.
+    caps_shift: bool,
+    /// Keys pressed by `Action::Timed` and when each is released.
+    timed_releases: Vec<(u16, Instant)>,
+    /// NumLock is on, as far as spacefn can tell, see `set_numlock`.
+    numlock: bool,
+    /// NumLock was turned on for the Fn layer and goes off with it, see numpad_numlock.
+    numlock_forced: bool,
+    pub stats: Stats,
 }
 
 impl StateMachine {
     pub fn new(config: crate::config::Config) -> Self {
         Self {
+            leader: LeaderTrie::new(&config.leader),
             state: State::Idle,
             buffer: KeyBuffer::new(),
             config,
+            decide_deadline: None,
+            layer_armed_at: None,
+            overlap_until: None,
+            layer_down: Vec::new(),
+            combo_sent: Vec::new(),
+            combo_deadline: None,
+            pending_tap: None,
+            double: false,
+            held: Vec::new(),
+            base_active: Vec::new(),
+            neutralized: Vec::new(),
+            pending_hold: None,
+            held_taps: Vec::new(),
+            leader_keys: Vec::new(),
+            leader_deadline: None,
+            swallowed: Vec::new(),
+            grace_until: None,
+            previous_configs: Vec::new(),
+            deferred: None,
+            disabled: Vec::new(),
+            auto_repeat: None,
+            caps_word: false,
+            caps_shift: false,
+            timed_releases: Vec::new(),
+            numlock: false,
+            numlock_forced: false,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: crate::config::Config) {
+        self.leader = LeaderTrie::new(&config.leader);
+        self.config = config;
+    }
+
+    /// Tells the state machine whether NumLock is on, e.g. from the keyboard's LED. Presses
+    /// of NumLock toggle it from there.
+    pub fn set_numlock(&mut self, on: bool) {
+        self.numlock = on;
+    }
+
+    /// Swaps the Fn layer mappings and numpad_numlock, which goes with the numpad preset,
+    /// first letting go of keys pressed under the old ones.
+    pub fn set_mappings<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        keys_map: Vec<[u32; 3]>,
+        numpad_numlock: bool,
+    ) -> anyhow::Result<()> {
+        self.release_all(sink)?;
+        self.config.keys_map = keys_map;
+        self.config.numpad_numlock = numpad_numlock;
+        Ok(())
+    }
+
+    /// Switches the Fn layer mapping of `source` on or off until restart, releasing it
+    /// first if it is held.
+    pub fn set_mapping_enabled<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        source: u16,
+        enabled: bool,
+    ) -> anyhow::Result<()> {
+        if !self
+            .config
+            .keys_map
+            .iter()
+            .any(|m| m[0] == u32::from(source))
+        {
+            anyhow::bail!("No mapping for key {}", source);
+        }
+        // Let go of what the held source key emitted under the old setting; the source
+        // key is still down and its release has nothing left to do.
+        if self.state == State::Shift && self.buffer.contains(source) {
+            self.send_mapped_key(sink, source, KeyValue::Release)?;
+            self.buffer.remove(source);
+            self.swallowed.push(source);
+        } else if self.state == State::Shift
+            && self.held.contains(&source)
+            && !self.swallowed.contains(&source)
+            && !self.base_active.iter().any(|&(src, _, _)| src == source)
+        {
+            // Not buffered, so it went out unmapped.
+            sink.send_key(source, 0)?;
+            self.swallowed.push(source);
+        }
+        self.disabled.retain(|&c| c != source);
+        if !enabled {
+            self.disabled.push(source);
+        }
+        Ok(())
+    }
+
+    /// Drops key events for grab_grace_ms from `now`, the tail of typing from before the grab.
+    pub fn start_grace(&mut self, now: Instant) {
+        let grace = self.config.grab_grace_ms;
+        self.grace_until = (grace > 0).then(|| now + Duration::from_millis(grace));
+    }
+
+    /// Swaps in `config`, remembering the current one for `revert_config`.
+    pub fn load_config<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        config: crate::config::Config,
+    ) -> anyhow::Result<()> {
+        self.switch_config(sink, config, true)
+    }
+
+    /// Swaps in `config` re-read from disk. Like `load_config` it waits for or releases
+    /// held keys, but `revert_config` does not come back to the replaced one.
+    pub fn reload_config<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        config: crate::config::Config,
+    ) -> anyhow::Result<()> {
+        self.switch_config(sink, config, false)
+    }
+
+    fn switch_config<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        config: crate::config::Config,
+        remember: bool,
+    ) -> anyhow::Result<()> {
+        if self.config.switch_while_held == SwitchPolicy::Defer && self.state != State::Idle {
+            log::info!("Keys are held in the Fn layer, switching config once they are released");
+            self.deferred = Some((config, remember));
+            return Ok(());
+        }
+        self.release_all(sink)?;
+        self.deferred = None;
+        self.swap_config(config, remember);
+        Ok(())
+    }
+
+    fn swap_config(&mut self, config: crate::config::Config, remember: bool) {
+        let previous = std::mem::take(&mut self.config);
+        if remember {
+            self.previous_configs.push(previous);
+        }
+        self.set_config(config);
+    }
+
+    /// The config `revert_config` goes back to.
+    pub fn previous_config(&self) -> Option<&crate::config::Config> {
+        if self.deferred.is_some() {
+            // Reverting only cancels the pending switch.
+            return Some(&self.config);
         }
+        self.previous_configs.last()
+    }
+
+    /// Goes back to the config replaced by the last `load_config`.
+    pub fn revert_config<S: KeySink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        if self.deferred.take().is_some() {
+            return Ok(());
+        }
+        let Some(previous) = self.previous_configs.pop() else {
+            anyhow::bail!("No earlier config to revert to");
+        };
+        self.release_all(sink)?;
+        self.set_config(previous);
+        Ok(())
     }
 
     pub fn state(&self) -> State {
@@ -103,7 +444,15 @@ impl StateMachine {
     }
 
     pub fn map_key(&self, original: u16) -> (u16, Option<u16>) {
-        for mapping in &self.config.keys_map {
+        if self.disabled.contains(&original) {
+            return (original, None);
+        }
+        let double_map = if self.double {
+            &self.config.double_keys_map[..]
+        } else {
+            &[]
+        };
+        for mapping in double_map.iter().chain(&self.config.keys_map) {
             if mapping[0] == u32::from(original) {
                 let mapped = if mapping[1] != 0 {
                     mapping[1] as u16
@@ -123,150 +472,1251 @@ impl StateMachine {
 
     pub fn set_state(&mut self, state: State) {
         self.state = state;
-        if state == State::Decide {
+        if state != State::Shift {
             self.buffer.clear();
+            self.auto_repeat = None;
+        }
+        if state != State::Decide {
+            self.decide_deadline = None;
+            self.layer_armed_at = None;
+            self.overlap_until = None;
+        }
+        if state == State::Idle {
+            self.double = false;
+            self.layer_down.clear();
+            self.leader_keys.clear();
+            self.leader_deadline = None;
         }
     }
 
     pub fn buffer(&self) -> &KeyBuffer {
         &self.buffer
     }
-}
-
-pub fn check_permissions(device_path: &str) -> anyhow::Result<()> {
-    let _device_file = File::open(device_path)?;
 
-    let _uinput_file = File::open("/dev/uinput")?;
+    /// Time at which a pending Decide, hold-tap, layer combo or leader sequence resolves,
+    /// or the next software repeat is due, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        let hold_deadline = self.pending_hold.as_ref().map(|p| p.deadline);
+        [
+            self.decide_deadline,
+            hold_deadline,
+            self.leader_deadline,
+            self.pending_tap,
+            self.combo_deadline,
+            self.auto_repeat.map(|(_, at)| at),
+            self.timed_releases.iter().map(|&(_, at)| at).min(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
 
-    Ok(())
-}
+    /// Feeds one key event through the state machine and returns the new state.
+    pub fn handle_key<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        code: u16,
+        value: i32,
+        now: Instant,
+    ) -> anyhow::Result<State> {
+        if self.grace_until.is_some_and(|until| now < until) {
+            return Ok(self.state);
+        }
+        let Some(key_value) = KeyValue::parse(value) else {
+            log::debug!("Forwarding key {} with unknown value {}", code, value);
+            sink.send_key(code, value)?;
+            return Ok(self.state);
+        };
+        self.poll_timeout(sink, now)?;
+        if code == KEY_NUMLOCK && key_value == KeyValue::Press {
+            self.numlock = !self.numlock;
+            // The user chose a NumLock state, leave it as they set it.
+            self.numlock_forced = false;
+        }
+        match key_value {
+            KeyValue::Press if !self.held.contains(&code) => self.held.push(code),
+            KeyValue::Release => self.held.retain(|&c| c != code),
+            _ => {}
+        }
+        if let Some(pending) = self.pending_hold.as_mut() {
+            if code != pending.code {
+                pending.queued.push((code, value, now));
+                return Ok(self.state);
+            }
+            if key_value == KeyValue::Release {
+                // Released before its hold_ms: it was a tap.
+                let pending = self.pending_hold.take().unwrap();
+                sink.send_key(code, 1)?;
+                sink.send_key(code, 0)?;
+                self.replay(sink, pending.queued)?;
+            }
+            return Ok(self.state);
+        }
+        if let Some(pos) = self.held_taps.iter().position(|&(src, _)| src == code) {
+            if key_value == KeyValue::Release {
+                let (_, hold) = self.held_taps.remove(pos);
+                sink.send_key(hold, 0)?;
+            }
+            return Ok(self.state);
+        }
+        let layer_press = self.is_layer_key(code) && key_value == KeyValue::Press;
+        if self.pending_tap.is_some() && !layer_press {
+            self.flush_pending_tap(sink)?;
+        }
+        if let Some(pos) = self.swallowed.iter().position(|&c| c == code) {
+            if key_value == KeyValue::Release {
+                self.swallowed.remove(pos);
+            }
+            return Ok(self.state);
+        }
+        match self.state {
+            State::Idle => self.handle_idle_key(sink, code, key_value, now)?,
+            State::Decide => self.handle_decide_key(sink, code, key_value, now)?,
+            State::Shift => self.handle_shift_key(sink, code, key_value, now)?,
+        }
+        Ok(self.state)
+    }
 
-pub fn list_input_devices() -> Vec<InputDeviceInfo> {
-    let mut devices = Vec::new();
+    /// Brings our view of the held keys in line with `pressed`, the device's real key state
+    /// after a SYN_DROPPED. Missed releases and presses are replayed through the state
+    /// machine, so a layer key that was let go during the gap ends Decide or Shift as usual.
+    pub fn resync<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        pressed: &[u16],
+        now: Instant,
+    ) -> anyhow::Result<State> {
+        self.stats.syn_dropped += 1;
+        self.release_missing(sink, pressed, now)?;
+        let missed: Vec<u16> = pressed
+            .iter()
+            .copied()
+            .filter(|code| !self.held.contains(code))
+            .collect();
+        for code in missed {
+            self.handle_key(sink, code, KeyValue::Press as i32, now)?;
+        }
+        Ok(self.state)
+    }
 
-    let enumeration = evdev::enumerate();
-    for (path, device) in enumeration {
-        if let Some(name) = device.name() {
-            devices.push(InputDeviceInfo {
-                path: path.to_string_lossy().to_string(),
-                name: name.to_string(),
-            });
+    /// Releases keys we believe are held but that `pressed`, the device's real key state,
+    /// no longer has down. Covers releases lost to rollover limits on cheap keyboards.
+    pub fn reconcile<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        pressed: &[u16],
+        now: Instant,
+    ) -> anyhow::Result<State> {
+        let released = self.release_missing(sink, pressed, now)?;
+        if released > 0 {
+            log::warn!("Released {} key(s) that were no longer held", released);
+            self.stats.reconciled += released as u64;
         }
+        Ok(self.state)
     }
 
-    devices
-}
+    fn release_missing<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        pressed: &[u16],
+        now: Instant,
+    ) -> anyhow::Result<usize> {
+        let mut released: Vec<u16> = self
+            .held
+            .iter()
+            .copied()
+            .filter(|code| !pressed.contains(code))
+            .collect();
+        // Let go of the layer key last so mapped keys are released inside the layer.
+        released.sort_by_key(|&code| self.is_layer_key(code));
+        for &code in &released {
+            self.handle_key(sink, code, KeyValue::Release as i32, now)?;
+        }
+        Ok(released.len())
+    }
 
-#[derive(Debug, Clone)]
-pub struct InputDeviceInfo {
-    pub path: String,
-    pub name: String,
-}
+    /// Resolves Decide to Shift once the decide timeout has expired.
+    pub fn poll_timeout<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        now: Instant,
+    ) -> anyhow::Result<State> {
+        while let Some(pending) = self.pending_hold.take_if(|p| now >= p.deadline) {
+            // Held past its hold_ms: act as the hold key.
+            sink.send_key(pending.hold, 1)?;
+            self.held_taps.push((pending.code, pending.hold));
+            self.replay(sink, pending.queued)?;
+        }
+        if self.state == State::Decide && self.decide_deadline.is_some_and(|d| now >= d) {
+            self.commit_shift(sink, now)?;
+        }
+        if let Some((code, _)) = self.auto_repeat.filter(|&(_, at)| now >= at) {
+            self.send_mapped_key(sink, code, KeyValue::Repeat)?;
+            let interval = self
+                .config
+                .auto_repeat_interval_ms
+                .unwrap_or(AUTO_REPEAT_INTERVAL_MS);
+            self.auto_repeat = Some((code, now + Duration::from_millis(interval)));
+        }
+        if self.pending_tap.is_some_and(|d| now >= d) {
+            self.flush_pending_tap(sink)?;
+        }
+        if self.combo_deadline.is_some_and(|d| now >= d) {
+            self.flush_combo(sink)?;
+        }
+        for (code, _) in self.timed_releases.extract_if(.., |&mut (_, at)| now >= at) {
+            sink.send_key(code, 0)?;
+        }
+        if self.leader_deadline.is_some_and(|d| now >= d) {
+            // Fire a sequence that is also the prefix of a longer one, else abort.
+            if let Match::Partial(Some(action)) = self.leader.lookup(&self.leader_keys) {
+                self.send_leader_action(sink, action)?;
+            }
+            self.leader_keys.clear();
+            self.leader_deadline = None;
+        }
+        if self.state == State::Idle {
+            if let Some((config, remember)) = self.deferred.take() {
+                self.swap_config(config, remember);
+            }
+        }
+        Ok(self.state)
+    }
 
-pub fn open_device(path: &str) -> anyhow::Result<Device> {
-    let device = Device::open(path)?;
-    Ok(device)
-}
+    /// Feeds events held back by a pending hold-tap through the state machine.
+    fn replay<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        queued: Vec<(u16, i32, Instant)>,
+    ) -> anyhow::Result<()> {
+        for (code, value, at) in queued {
+            self.handle_key(sink, code, value, at)?;
+        }
+        Ok(())
+    }
 
-pub fn create_uinput_device(input_device: &Device) -> anyhow::Result<evdev::uinput::VirtualDevice> {
-    let keys = input_device.supported_keys();
+    fn is_layer_key(&self, code: u16) -> bool {
+        self.config.layer_key.codes().contains(&code)
+    }
 
-    let mut key_set = AttributeSet::<Key>::new();
-    if let Some(k) = keys {
-        for key in k.iter() {
-            key_set.insert(key);
+    fn handle_idle_key<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        code: u16,
+        value: KeyValue,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        if self.caps_word && value == KeyValue::Press {
+            self.caps_word_press(sink, code)?;
+        }
+        if !self.is_layer_key(code) {
+            if value == KeyValue::Press {
+                self.flush_combo(sink)?;
+                if let Some(hold_tap) = self.config.hold_tap.iter().find(|h| h.key == code) {
+                    let hold_ms = hold_tap.hold_ms.unwrap_or(DECIDE_TIMEOUT_MS);
+                    self.pending_hold = Some(PendingHold {
+                        code,
+                        hold: hold_tap.hold,
+                        deadline: now + Duration::from_millis(hold_ms),
+                        queued: Vec::new(),
+                    });
+                    return Ok(());
+                }
+            }
+            return self.send_base_key(sink, code, value);
+        }
+        match value {
+            KeyValue::Press => {
+                if !self.layer_down.contains(&code) {
+                    self.layer_down.push(code);
+                }
+                let codes = self.config.layer_key.codes();
+                if !codes.iter().all(|c| self.layer_down.contains(c)) {
+                    self.flush_pending_tap(sink)?;
+                    // Held back so completing the combo sends nothing, not a tap of Alt.
+                    self.combo_deadline
+                        .get_or_insert(now + Duration::from_millis(DECIDE_TIMEOUT_MS));
+                    return Ok(());
+                }
+                self.combo_deadline = None;
+                // Members let through before the combo completed, take them back.
+                for held in std::mem::take(&mut self.combo_sent) {
+                    sink.send_key(held, 0)?;
+                }
+                self.set_state(State::Decide);
+                self.double = self.pending_tap.take().is_some();
+                self.decide_deadline = Some(now + Duration::from_millis(DECIDE_TIMEOUT_MS));
+                let min_hold = self.config.min_layer_hold_ms;
+                self.layer_armed_at = (min_hold > 0).then(|| now + Duration::from_millis(min_hold));
+                let overlap = self.config.typing_overlap_ms;
+                self.overlap_until = (overlap > 0).then(|| now + Duration::from_millis(overlap));
+                Ok(())
+            }
+            KeyValue::Release => {
+                let down = self.layer_down.iter().position(|&c| c == code);
+                if let Some(pos) = down {
+                    self.layer_down.remove(pos);
+                }
+                if let Some(pos) = self.combo_sent.iter().position(|&c| c == code) {
+                    self.combo_sent.remove(pos);
+                    sink.send_key(code, 0)?;
+                } else if down.is_some() {
+                    // Held back and let go alone, the user tapped it.
+                    sink.send_key(code, 1)?;
+                    sink.send_key(code, 0)?;
+                }
+                // Members still held when the layer ended were already released.
+                if self.layer_down.iter().all(|c| self.combo_sent.contains(c)) {
+                    self.combo_deadline = None;
+                }
+                Ok(())
+            }
+            KeyValue::Repeat if self.combo_sent.contains(&code) => {
+                sink.send_key(code, value as i32)
+            }
+            KeyValue::Repeat => Ok(()),
         }
     }
 
-    let device = evdev::uinput::VirtualDeviceBuilder::new()?
-        .name("spacefn virtual keyboard")
-        .with_keys(&key_set)?
-        .build()?;
-
-    Ok(device)
-}
+    /// Lets through the members of a layer combo held back so far, once another key or
+    /// the timeout shows the rest is not coming.
+    fn flush_combo<S: KeySink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        self.combo_deadline = None;
+        for &code in &self.layer_down {
+            if !self.combo_sent.contains(&code) {
+                sink.send_key(code, 1)?;
+                self.combo_sent.push(code);
+            }
+        }
+        Ok(())
+    }
 
-pub fn send_key(
-    uinput: &mut evdev::uinput::VirtualDevice,
-    code: u16,
-    value: i32,
-) -> anyhow::Result<()> {
-    let event = InputEvent::new(EventType::KEY, code, value);
-    uinput.emit(&[event])?;
-    Ok(())
-}
+    /// Shifts letters while caps word is armed; other keys let go of Shift first, and
+    /// anything but a layer key, modifier or `continues_caps_word` key ends it.
+    fn caps_word_press<S: KeySink>(&mut self, sink: &mut S, code: u16) -> anyhow::Result<()> {
+        let out = self.base_lookup(code).map_or(code, |(to, _)| to);
+        if modifier_of(out).is_some() {
+            return Ok(());
+        }
+        if is_letter(out) {
+            if !self.caps_shift {
+                sink.send_key(KEY_LEFTSHIFT, 1)?;
+                self.caps_shift = true;
+            }
+            return Ok(());
+        }
+        self.release_caps_shift(sink)?;
+        if !self.is_layer_key(code) && !continues_caps_word(out) {
+            self.caps_word = false;
+        }
+        Ok(())
+    }
 
-pub fn forward_event(
-    uinput: &mut evdev::uinput::VirtualDevice,
-    event: &InputEvent,
-) -> anyhow::Result<()> {
-    uinput.emit(&[event.clone()])?;
-    Ok(())
-}
+    fn release_caps_shift<S: KeySink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        if std::mem::take(&mut self.caps_shift) {
+            sink.send_key(KEY_LEFTSHIFT, 0)?;
+        }
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Looks up the base remap for `code` under the modifiers currently held.
+    fn base_lookup(&self, code: u16) -> Option<(u16, Option<u16>)> {
+        let mut held: Vec<Modifier> = self
+            .held
+            .iter()
+            .filter(|&&c| c != code)
+            .filter_map(|&c| modifier_of(c))
+            .collect();
+        held.sort();
+        held.dedup();
+        self.config.base_map.iter().find_map(|m| {
+            let mut when = m.when.clone();
+            when.sort();
+            when.dedup();
+            if m.key == code && when == held {
+                Some((m.to, (m.ext != 0).then_some(m.ext)))
+            } else {
+                None
+            }
+        })
+    }
 
-    #[test]
-    fn test_key_buffer() {
-        let mut buffer = KeyBuffer::new();
+    fn send_base_key<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        code: u16,
+        value: KeyValue,
+    ) -> anyhow::Result<()> {
+        let active = self.base_active.iter().position(|&(src, _, _)| src == code);
+        match (value, active) {
+            (KeyValue::Press, None) => {
+                if let Some((to, ext)) = self.base_lookup(code) {
+                    if let Some(ext) = ext {
+                        sink.send_key(ext, 1)?;
+                    }
+                    sink.send_key(to, 1)?;
+                    self.base_active.push((code, to, ext));
+                    return Ok(());
+                }
+            }
+            (KeyValue::Repeat, Some(pos)) => {
+                return sink.send_key(self.base_active[pos].1, value as i32);
+            }
+            (KeyValue::Release, Some(_)) => {
+                self.release_base_key(sink, code)?;
+                return Ok(());
+            }
+            _ => {}
+        }
+        sink.send_key(code, value as i32)
+    }
 
-        assert!(!buffer.contains(1));
-        assert!(buffer.append(1));
-        assert!(buffer.contains(1));
-        assert!(!buffer.append(1));
+    /// Releases what a base remap of `code` pressed, even if the modifiers or the state
+    /// changed meanwhile. Returns whether `code` had one.
+    fn release_base_key<S: KeySink>(&mut self, sink: &mut S, code: u16) -> anyhow::Result<bool> {
+        let Some(pos) = self.base_active.iter().position(|&(src, _, _)| src == code) else {
+            return Ok(false);
+        };
+        let (_, to, ext) = self.base_active.remove(pos);
+        sink.send_key(to, 0)?;
+        if let Some(ext) = ext {
+            sink.send_key(ext, 0)?;
+        }
+        Ok(true)
+    }
 
-        assert!(buffer.remove(1));
-        assert!(!buffer.contains(1));
+    fn handle_decide_key<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        code: u16,
+        value: KeyValue,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        if self.is_layer_key(code) {
+            if value == KeyValue::Release {
+                if !self.double && self.buffer.is_empty() && !self.config.double_keys_map.is_empty()
+                {
+                    let window = self.config.double_tap_ms.unwrap_or(DECIDE_TIMEOUT_MS);
+                    self.set_state(State::Idle);
+                    self.pending_tap = Some(now + Duration::from_millis(window));
+                    return Ok(());
+                }
+                self.tap_layer(sink)?;
+            }
+            return Ok(());
+        }
+        let typing = self.layer_armed_at.is_some_and(|at| now < at)
+            || (self.buffer.is_empty() && self.overlap_until.is_some_and(|until| now < until));
+        if value == KeyValue::Press && typing {
+            // Too soon after the layer key to be a chord, the user is typing.
+            self.buffer.append(code);
+            return self.tap_layer(sink);
+        }
+        match value {
+            KeyValue::Press => {
+                if self.config.instant_keys.contains(&code)
+                    || self.leader.starts_with(code)
+                    || self.action_for(code).is_some()
+                {
+                    self.commit_shift(sink, now)?;
+                    return self.handle_shift_key(sink, code, value, now);
+                }
+                if !self.buffer.contains(code) && !self.buffer.append(code) {
+                    // Buffer is full, stop waiting and commit to the layer.
+                    self.commit_shift(sink, now)?;
+                    return self.handle_shift_key(sink, code, value, now);
+                }
+            }
+            KeyValue::Release if self.buffer.contains(code) => {
+                self.commit_shift(sink, now)?;
+                return self.handle_shift_key(sink, code, value, now);
+            }
+            KeyValue::Release => {
+                if !self.release_base_key(sink, code)? {
+                    sink.send_key(code, value as i32)?;
+                }
+            }
+            KeyValue::Repeat => {}
+        }
+        Ok(())
+    }
 
-        for i in 0..MAX_BUFFER {
-            assert!(buffer.append(i as u16));
+    /// Types a held back tap of the layer key now that no second press follows.
+    fn flush_pending_tap<S: KeySink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        if self.pending_tap.take().is_some() {
+            for &layer_code in self.config.layer_key.codes() {
+                sink.send_key(layer_code, 1)?;
+                sink.send_key(layer_code, 0)?;
+            }
         }
-        assert!(!buffer.append(100));
+        Ok(())
     }
 
-    #[test]
-    fn test_key_buffer_clear() {
-        let mut buffer = KeyBuffer::new();
-        buffer.append(1);
-        buffer.append(2);
-        assert_eq!(buffer.len(), 2);
+    /// Resolves Decide as a plain tap of the layer key followed by the buffered keys.
+    fn tap_layer<S: KeySink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        // The layer key types a space, which ends a word.
+        self.caps_word = false;
+        // A double tap types the layer key twice.
+        let taps = if self.double { 2 } else { 1 };
+        for _ in 0..taps {
+            for &layer_code in self.config.layer_key.codes() {
+                sink.send_key(layer_code, 1)?;
+                sink.send_key(layer_code, 0)?;
+            }
+        }
+        for &code in self.buffer.iter() {
+            sink.send_key(code, 1)?;
+        }
+        self.set_state(State::Idle);
+        Ok(())
+    }
 
-        buffer.clear();
-        assert!(buffer.is_empty());
+    fn handle_shift_key<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        code: u16,
+        value: KeyValue,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        if self.is_layer_key(code) {
+            if value == KeyValue::Release {
+                self.release_all(sink)?;
+            }
+            return Ok(());
+        }
+        if value == KeyValue::Release && self.release_base_key(sink, code)? {
+            // Base remapped before the layer began.
+            return Ok(());
+        }
+        if value == KeyValue::Release && self.swallowed.contains(&code) {
+            // A one-shot key released while Decide was being resolved.
+            self.swallowed.retain(|&c| c != code);
+            return Ok(());
+        }
+        if value == KeyValue::Release && self.neutralized.contains(&code) {
+            // Already released on entering the layer.
+            self.neutralized.retain(|&c| c != code);
+            return Ok(());
+        }
+        if value == KeyValue::Press {
+            if let Some(action) = self.action_for(code) {
+                match action.clone() {
+                    Action::Exec(command) => sink.exec(&command)?,
+                    Action::CapsWord(enabled) => self.caps_word = enabled,
+                    Action::Reset(true) => self.reset(sink)?,
+                    Action::Reset(false) => {}
+                    Action::Timed(timed) => self.timed_press(sink, timed, now)?,
+                }
+                self.swallowed.push(code);
+                return Ok(());
+            }
+        }
+        if value == KeyValue::Press && self.handle_leader_key(sink, code, now)? {
+            return Ok(());
+        }
+        match value {
+            KeyValue::Press => {
+                if self.press_mapped(sink, code, now)? {
+                    self.buffer.append(code);
+                }
+            }
+            KeyValue::Repeat => {
+                if self.repeat_mode(code) == RepeatMode::Held {
+                    self.send_mapped_key(sink, code, value)?;
+                }
+            }
+            KeyValue::Release => {
+                if self.auto_repeat.is_some_and(|(c, _)| c == code) {
+                    self.auto_repeat = None;
+                }
+                self.send_mapped_key(sink, code, value)?;
+                self.buffer.remove(code);
+            }
+        }
+        Ok(())
     }
 
-    #[test]
-    fn test_key_buffer_remove_middle() {
-        let mut buffer = KeyBuffer::new();
-        buffer.append(1);
-        buffer.append(2);
-        buffer.append(3);
+    fn repeat_mode(&self, code: u16) -> RepeatMode {
+        self.config
+            .repeat
+            .iter()
+            .find(|m| m.key == code)
+            .map_or(RepeatMode::Held, |m| m.repeat)
+    }
 
-        buffer.remove(2);
-        assert!(!buffer.contains(2));
-        assert!(buffer.contains(1));
-        assert!(buffer.contains(3));
+    /// Presses the mapping for `code` as its repeat mode says. Returns whether the mapping
+    /// stays pressed, to be released with the layer.
+    fn press_mapped<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        code: u16,
+        now: Instant,
+    ) -> anyhow::Result<bool> {
+        // Like a keyboard, only the last key pressed repeats.
+        self.auto_repeat = None;
+        self.assert_numlock(sink, code)?;
+        match self.repeat_mode(code) {
+            RepeatMode::OneShot => {
+                self.send_mapped_key(sink, code, KeyValue::Press)?;
+                self.send_mapped_key(sink, code, KeyValue::Release)?;
+                // Its repeats and release have nothing left to do.
+                self.swallowed.push(code);
+                Ok(false)
+            }
+            RepeatMode::Held => self.send_mapped_key(sink, code, KeyValue::Press),
+            RepeatMode::AutoRepeat => {
+                let delay = self
+                    .config
+                    .auto_repeat_delay_ms
+                    .unwrap_or(AUTO_REPEAT_DELAY_MS);
+                self.auto_repeat = Some((code, now + Duration::from_millis(delay)));
+                self.send_mapped_key(sink, code, KeyValue::Press)
+            }
+        }
     }
 
-    #[test]
-    fn test_state_transitions() {
-        let config = crate::config::Config::default();
-        let mut sm = StateMachine::new(config);
+    /// Turns NumLock on before `code` is pressed if it maps to a keypad digit, so the digit
+    /// comes out whatever the user's NumLock state. `release_all` turns it back off.
+    fn assert_numlock<S: KeySink>(&mut self, sink: &mut S, code: u16) -> anyhow::Result<()> {
+        if !self.config.numpad_numlock || self.numlock || !needs_numlock(self.map_key(code).0) {
+            return Ok(());
+        }
+        sink.send_key(KEY_NUMLOCK, 1)?;
+        sink.send_key(KEY_NUMLOCK, 0)?;
+        self.numlock = true;
+        self.numlock_forced = true;
+        Ok(())
+    }
 
-        assert_eq!(sm.state(), State::Idle);
+    fn restore_numlock<S: KeySink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        if std::mem::take(&mut self.numlock_forced) {
+            sink.send_key(KEY_NUMLOCK, 1)?;
+            sink.send_key(KEY_NUMLOCK, 0)?;
+            self.numlock = false;
+        }
+        Ok(())
+    }
 
-        sm.set_state(State::Decide);
-        assert_eq!(sm.state(), State::Decide);
-        assert!(sm.buffer.is_empty());
+    /// Presses the key of a timed action; `poll_timeout` releases it after its hold_ms, even if
+    /// the source key is let go sooner. Pressed again before then, it is released and re-pressed
+    /// and the time starts over.
+    fn timed_press<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        timed: TimedPress,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        if let Some(pos) = self
+            .timed_releases
+            .iter()
+            .position(|&(c, _)| c == timed.key)
+        {
+            self.timed_releases.remove(pos);
+            sink.send_key(timed.key, 0)?;
+        }
+        sink.send_key(timed.key, 1)?;
+        self.timed_releases
+            .push((timed.key, now + Duration::from_millis(timed.hold_ms)));
+        Ok(())
+    }
 
-        sm.set_state(State::Shift);
-        assert_eq!(sm.state(), State::Shift);
+    fn action_for(&self, code: u16) -> Option<&Action> {
+        self.config
+            .actions
+            .iter()
+            .find(|m| m.key == code)
+            .map(|m| &m.action)
     }
 
-    #[test]
-    fn test_key_map_no_mapping() {
+    /// Feeds a press to the leader sequence matcher. Returns whether it was consumed; a key
+    /// that breaks a sequence aborts it and is handled as usual.
+    fn handle_leader_key<S: KeySink>(
+        &mut self,
+        sink: &mut S,
+        code: u16,
+        now: Instant,
+    ) -> anyhow::Result<bool> {
+        if self.leader_keys.is_empty() && !self.leader.starts_with(code) {
+            return Ok(false);
+        }
+        self.leader_keys.push(code);
+        match self.leader.lookup(&self.leader_keys) {
+            Match::None => {
+                self.leader_keys.clear();
+                self.leader_deadline = None;
+                if !self.leader.starts_with(code) {
+                    return Ok(false);
+                }
+                // The aborting key begins a sequence of its own.
+                return self.handle_leader_key(sink, code, now);
+            }
+            Match::Partial(_) => {
+                let timeout = self.config.leader_timeout_ms.unwrap_or(LEADER_TIMEOUT_MS);
+                self.leader_deadline = Some(now + Duration::from_millis(timeout));
+            }
+            Match::Complete(action) => {
+                self.leader_keys.clear();
+                self.leader_deadline = None;
+                self.send_leader_action(sink, action)?;
+            }
+        }
+        if !self.swallowed.contains(&code) {
+            self.swallowed.push(code);
+        }
+        Ok(true)
+    }
+
+    fn send_leader_action<S: KeySink>(&self, sink: &mut S, action: usize) -> anyhow::Result<()> {
+        let send = &self.config.leader[action].send;
+        for &code in send {
+            sink.send_key(code, 1)?;
+        }
+        for &code in send.iter().rev() {
+            sink.send_key(code, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Releases every key held by the layer and returns to Idle.
+    pub fn release_all<S: KeySink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        self.release_caps_shift(sink)?;
+        if self.state == State::Shift {
+            for &code in self.buffer.iter() {
+                self.send_mapped_key(sink, code, KeyValue::Release)?;
+            }
+            for code in std::mem::take(&mut self.neutralized) {
+                if self.held.contains(&code) {
+                    sink.send_key(code, 1)?;
+                }
+            }
+        }
+        self.restore_numlock(sink)?;
+        self.set_state(State::Idle);
+        Ok(())
+    }
+
+    /// Releases everything spacefn holds down, whatever the state, and returns to Idle.
+    /// Keys buffered in Decide or waiting on a hold-tap are dropped.
+    pub fn reset<S: KeySink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        self.release_all(sink)?;
+        for (_, hold) in std::mem::take(&mut self.held_taps) {
+            sink.send_key(hold, 0)?;
+        }
+        for (_, to, ext) in std::mem::take(&mut self.base_active) {
+            sink.send_key(to, 0)?;
+            if let Some(ext) = ext {
+                sink.send_key(ext, 0)?;
+            }
+        }
+        for (code, _) in std::mem::take(&mut self.timed_releases) {
+            sink.send_key(code, 0)?;
+        }
+        self.pending_hold = None;
+        self.pending_tap = None;
+        self.combo_deadline = None;
+        self.swallowed.clear();
+        self.caps_word = false;
+        Ok(())
+    }
+
+    /// Resets and also releases the keys passed through, before handing the keyboard back,
+    /// e.g. the Alt of an Alt+Tab that focused an app in pause_for_apps.
+    pub fn flush<S: KeySink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        self.reset(sink)?;
+        for code in std::mem::take(&mut self.held) {
+            sink.send_key(code, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Emits mapped presses for everything buffered during Decide and enters Shift.
+    fn commit_shift<S: KeySink>(&mut self, sink: &mut S, now: Instant) -> anyhow::Result<()> {
+        if self.config.neutralize_modifiers {
+            // Modifiers held since before the layer were passed through, let go of them.
+            self.neutralized = self
+                .held
+                .iter()
+                .copied()
+                .filter(|&c| modifier_of(c).is_some())
+                .filter(|&c| !self.buffer.contains(c) && !self.is_layer_key(c))
+                .collect();
+            for &code in &self.neutralized {
+                sink.send_key(code, 0)?;
+            }
+        }
+        let mut flush: Vec<u16> = self.buffer.iter().copied().collect();
+        if self.config.modifiers_first {
+            // Stable, so keys keep their typed order within each group.
+            flush.sort_by_key(|&code| !self.is_modifier_output(code));
+        }
+        for code in flush {
+            self.press_mapped(sink, code, now)?;
+            if self.repeat_mode(code) == RepeatMode::OneShot {
+                self.buffer.remove(code);
+            }
+        }
+        self.set_state(State::Shift);
+        Ok(())
+    }
+
+    /// Whether `code` maps to a key that modifiers_first treats as a modifier.
+    fn is_modifier_output(&self, code: u16) -> bool {
+        let (mapped, _) = self.map_key(code);
+        if self.config.modifier_keys.is_empty() {
+            modifier_of(mapped).is_some()
+        } else {
+            self.config.modifier_keys.contains(&mapped)
+        }
+    }
+
+    fn send_mapped_key<S: KeySink>(
+        &self,
+        sink: &mut S,
+        code: u16,
+        value: KeyValue,
+    ) -> anyhow::Result<bool> {
+        let (mapped_code, ext_code) = self.map_key(code);
+        let actual_code = if mapped_code != 0 { mapped_code } else { code };
+        let remapped = actual_code != code || ext_code.is_some();
+        if remapped && value == KeyValue::Press && log::log_enabled!(log::Level::Trace) {
+            log::trace!("Mapped {}", describe_mapping(code, actual_code, ext_code));
+        }
+        let mut order = [ext_code, Some(actual_code)];
+        // Releases undo the presses in reverse.
+        if (self.config.ext_order == ExtOrder::After) == (value != KeyValue::Release) {
+            order.reverse();
+        }
+        for key in order.into_iter().flatten() {
+            sink.send_key(key, value as i32)?;
+        }
+        // An extended key alone, e.g. [104, 0, 109], still has to be released with the layer.
+        Ok((mapped_code != 0 && mapped_code != code) || ext_code.is_some())
+    }
+}
+
+/// Runs `command` through `sh -c` on a worker thread that waits for it, so it is reaped.
+pub fn spawn_command(command: &str) {
+    let command = command.to_string();
+    std::thread::spawn(move || {
+        log::info!("Running {}", command);
+        match std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .status()
+        {
+            Ok(status) if !status.success() => {
+                log::warn!("Command {:?} exited with {}", command, status)
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Failed to run {:?}: {}", command, e),
+        }
+    });
+}
+
+pub fn check_permissions(device_path: &str) -> anyhow::Result<()> {
+    let _device_file = File::open(device_path)?;
+
+    let _uinput_file = File::open("/dev/uinput")?;
+
+    Ok(())
+}
+
+pub fn list_input_devices() -> Vec<InputDeviceInfo> {
+    let mut devices = Vec::new();
+
+    let links = stable_links(&STABLE_LINK_DIRS.map(Path::new));
+    let enumeration = evdev::enumerate();
+    for (path, device) in enumeration {
+        if let Some(name) = device.name() {
+            devices.push(InputDeviceInfo {
+                stable_path: links.get(&path).cloned(),
+                path: path.to_string_lossy().to_string(),
+                name: name.to_string(),
+            });
+        }
+    }
+
+    without_own_device(devices)
+}
+
+/// Drops our virtual keyboard, reading it back would feed our output into our input.
+fn without_own_device(mut devices: Vec<InputDeviceInfo>) -> Vec<InputDeviceInfo> {
+    devices.retain(|d| d.name != VIRTUAL_DEVICE_NAME);
+    devices
+}
+
+/// Refuses spacefn's own virtual keyboard as an input device.
+pub fn ensure_not_own_device(device: &RawDevice) -> anyhow::Result<()> {
+    if device.name() == Some(VIRTUAL_DEVICE_NAME) {
+        anyhow::bail!("Refusing to read from the spacefn virtual keyboard itself");
+    }
+    Ok(())
+}
+
+/// Why a device looks like software rather than a keyboard, e.g. another remapper's output
+/// or a remote input tool. Grabbing one can loop our output back or capture nothing.
+pub fn virtual_device_reason(bus: BusType, name: &str) -> Option<String> {
+    if bus == BusType::BUS_VIRTUAL {
+        Some("it is on the virtual bus".to_string())
+    } else if name.to_lowercase().contains("virtual") {
+        Some(format!("its name {:?} says virtual", name))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InputDeviceInfo {
+    pub path: String,
+    pub name: String,
+    /// A by-id or by-path link to the device, which unlike `path` survives reboots.
+    pub stable_path: Option<String>,
+}
+
+/// Where udev keeps stable links to input devices, preferred first.
+const STABLE_LINK_DIRS: [&str; 2] = ["/dev/input/by-id", "/dev/input/by-path"];
+
+/// Resolves a link such as `/dev/input/by-id/...` to the device node it points at. A path
+/// that cannot be resolved is returned as it is, for opening it to report why.
+pub fn resolve_device_path(path: &str) -> String {
+    std::fs::canonicalize(path).map_or_else(|_| path.to_string(), |p| p.display().to_string())
+}
+
+/// Maps device nodes to the first link to them in `dirs`, keeping the links' order.
+fn stable_links(dirs: &[&Path]) -> HashMap<PathBuf, String> {
+    let mut links = HashMap::new();
+    for dir in dirs {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        let mut entries: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+        entries.sort();
+        for link in entries {
+            if let Ok(target) = std::fs::canonicalize(&link) {
+                links
+                    .entry(target)
+                    .or_insert_with(|| link.display().to_string());
+            }
+        }
+    }
+    links
+}
+
+pub fn open_device(path: &str) -> anyhow::Result<RawDevice> {
+    let resolved = resolve_device_path(path);
+    if resolved != path {
+        log::info!("Opening {} for {}", resolved, path);
+    }
+    let device = RawDevice::open(&resolved)?;
+    ensure_not_own_device(&device)?;
+    Ok(device)
+}
+
+pub fn supported_key_codes(device: &RawDevice) -> Vec<u16> {
+    device
+        .supported_keys()
+        .map(|keys| keys.iter().map(|k| k.code()).collect())
+        .unwrap_or_default()
+}
+
+/// Whether the device's NumLock LED is lit, false if it has none or it cannot be read.
+pub fn numlock_led(device: &RawDevice) -> bool {
+    device
+        .get_led_state()
+        .is_ok_and(|leds| leds.contains(evdev::LedCode::LED_NUML))
+}
+
+/// Every key code the config can emit.
+pub fn output_codes(config: &crate::config::Config) -> Vec<u16> {
+    let mut codes: Vec<u16> = config
+        .keys_map
+        .iter()
+        .flat_map(|m| [m[1], m[2]])
+        .filter(|&c| c != 0)
+        .map(|c| c as u16)
+        .collect();
+    codes.extend(config.base_map.iter().flat_map(|m| [m.to, m.ext]));
+    codes.extend(config.hold_tap.iter().map(|h| h.hold));
+    codes.extend(config.leader.iter().flat_map(|l| l.send.iter().copied()));
+    codes.extend(config.actions.iter().filter_map(|a| match a.action {
+        Action::Timed(timed) => Some(timed.key),
+        _ => None,
+    }));
+    if config.numpad_numlock {
+        codes.push(KEY_NUMLOCK);
+    }
+    codes.retain(|&c| c != 0);
+    codes.sort();
+    codes.dedup();
+    codes
+}
+
+/// Creates the output device with the source's keys plus `extra`, so mappings can target
+/// keys the keyboard itself lacks, such as mouse buttons or consumer keys above 255.
+pub fn create_uinput_device(
+    input_device: &RawDevice,
+    extra: &[u16],
+) -> anyhow::Result<evdev::uinput::VirtualDevice> {
+    let mut key_set = AttributeSet::<KeyCode>::new();
+    for code in requested_keys(input_device, extra) {
+        key_set.insert(KeyCode::new(code));
+    }
+
+    let device = evdev::uinput::VirtualDevice::builder()?
+        .name(VIRTUAL_DEVICE_NAME)
+        .with_keys(&key_set)?
+        .build()?;
+
+    Ok(device)
+}
+
+/// Keys the virtual keyboard is created with: the source's keys plus `extra`.
+pub fn requested_keys(input_device: &RawDevice, extra: &[u16]) -> Vec<u16> {
+    let mut keys = supported_key_codes(input_device);
+    for &code in extra.iter().filter(|&&c| c <= KEY_MAX) {
+        if !keys.contains(&code) {
+            keys.push(code);
+        }
+    }
+    keys
+}
+
+/// Compares the keys asked of the output device with those it has. `targets` are the
+/// mapping targets, named when missing as those mappings cannot work.
+pub fn registration_warnings(
+    requested: &[u16],
+    registered: &[u16],
+    targets: &[u16],
+) -> Vec<String> {
+    if registered.is_empty() {
+        return vec!["The virtual keyboard has no keys, nothing can be typed".to_string()];
+    }
+    let mut warnings = Vec::new();
+    let missing = requested.iter().filter(|c| !registered.contains(c)).count();
+    if missing > 0 {
+        warnings.push(format!(
+            "{} of {} keys are missing from the virtual keyboard",
+            missing,
+            requested.len()
+        ));
+    }
+    for code in targets {
+        if *code <= KEY_MAX && !registered.contains(code) {
+            warnings.push(format!(
+                "Target {} is missing from the virtual keyboard and cannot be sent",
+                code
+            ));
+        }
+    }
+    warnings
+}
+
+/// Where remapped keys go: our own virtual keyboard, or an existing one we write into.
+pub enum Output {
+    Virtual(evdev::uinput::VirtualDevice),
+    Attached(RawDevice),
+}
+
+impl KeySink for Output {
+    fn send_key(&mut self, code: u16, value: i32) -> anyhow::Result<()> {
+        match self {
+            Output::Virtual(uinput) => send_key(uinput, code, value),
+            Output::Attached(_) => {
+                self.forward_event(&InputEvent::new(EventType::KEY.0, code, value))
+            }
+        }
+    }
+
+    fn forward_event(&mut self, event: &InputEvent) -> anyhow::Result<()> {
+        match self {
+            Output::Virtual(uinput) => forward_event(uinput, event),
+            Output::Attached(device) => {
+                // Unlike uinput, writing to an event device does not end the frame for us.
+                let report = InputEvent::new(
+                    EventType::SYNCHRONIZATION.0,
+                    SynchronizationCode::SYN_REPORT.0,
+                    0,
+                );
+                device.send_events(&[*event, report])?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Output {
+    /// The keys the output device accepts, read back from the kernel.
+    pub fn registered_keys(&mut self) -> anyhow::Result<Vec<u16>> {
+        match self {
+            Output::Virtual(uinput) => {
+                let node = uinput
+                    .enumerate_dev_nodes_blocking()?
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("The virtual keyboard has no device node"))??;
+                Ok(supported_key_codes(&RawDevice::open(node)?))
+            }
+            Output::Attached(device) => Ok(supported_key_codes(device)),
+        }
+    }
+}
+
+/// Attaches to the device called `attach_name` if one exists, else creates our own
+/// virtual keyboard for `input_device`. `input_path` is never attached to, as writing
+/// to it would feed our output back into our input.
+pub fn create_output(
+    input_device: &RawDevice,
+    input_path: &str,
+    extra: &[u16],
+    attach_name: &str,
+) -> anyhow::Result<Output> {
+    if !attach_name.is_empty() {
+        let devices = evdev::enumerate()
+            .filter_map(|(path, device)| {
+                Some(InputDeviceInfo {
+                    path: path.to_string_lossy().to_string(),
+                    name: device.name()?.to_string(),
+                    stable_path: None,
+                })
+            })
+            .collect::<Vec<_>>();
+        match find_output_device(&devices, attach_name, &resolve_device_path(input_path)) {
+            Some(path) => {
+                log::info!("Writing to existing device {:?} at {}", attach_name, path);
+                return Ok(Output::Attached(RawDevice::open(path)?));
+            }
+            None => log::warn!(
+                "Output device {:?} not found, creating a virtual keyboard",
+                attach_name
+            ),
+        }
+    }
+    Ok(Output::Virtual(create_uinput_device(input_device, extra)?))
+}
+
+/// Path of the device called `name`, other than `input_path`.
+fn find_output_device<'a>(
+    devices: &'a [InputDeviceInfo],
+    name: &str,
+    input_path: &str,
+) -> Option<&'a str> {
+    devices
+        .iter()
+        .find(|d| d.name == name && d.path != input_path)
+        .map(|d| d.path.as_str())
+}
+
+pub fn send_key(
+    uinput: &mut evdev::uinput::VirtualDevice,
+    code: u16,
+    value: i32,
+) -> anyhow::Result<()> {
+    let event = InputEvent::new(EventType::KEY.0, code, value);
+    uinput.emit(&[event])?;
+    Ok(())
+}
+
+pub fn forward_event(
+    uinput: &mut evdev::uinput::VirtualDevice,
+    event: &InputEvent,
+) -> anyhow::Result<()> {
+    uinput.emit(&[event.clone()])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Vec<(u16, i32)>,
+        commands: Vec<String>,
+    }
+
+    impl KeySink for RecordingSink {
+        fn send_key(&mut self, code: u16, value: i32) -> anyhow::Result<()> {
+            self.events.push((code, value));
+            Ok(())
+        }
+
+        fn forward_event(&mut self, event: &InputEvent) -> anyhow::Result<()> {
+            self.events.push((event.code(), event.value()));
+            Ok(())
+        }
+
+        fn exec(&mut self, command: &str) -> anyhow::Result<()> {
+            self.commands.push(command.to_string());
+            Ok(())
+        }
+    }
+
+    fn nav_config() -> crate::config::Config {
+        crate::config::Config {
+            keys_map: vec![[36, 105, 0], [37, 108, 0], [38, 106, 0]], // J K L -> Left Down Right
+            instant_keys: vec![37],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_key_buffer() {
+        let mut buffer = KeyBuffer::new();
+
+        assert!(!buffer.contains(1));
+        assert!(buffer.append(1));
+        assert!(buffer.contains(1));
+        assert!(!buffer.append(1));
+
+        assert!(buffer.remove(1));
+        assert!(!buffer.contains(1));
+
+        for i in 0..MAX_BUFFER {
+            assert!(buffer.append(i as u16));
+        }
+        assert!(!buffer.append(100));
+    }
+
+    #[test]
+    fn test_key_buffer_clear() {
+        let mut buffer = KeyBuffer::new();
+        buffer.append(1);
+        buffer.append(2);
+        assert_eq!(buffer.len(), 2);
+
+        buffer.clear();
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_key_buffer_remove_middle() {
+        let mut buffer = KeyBuffer::new();
+        buffer.append(1);
+        buffer.append(2);
+        buffer.append(3);
+
+        buffer.remove(2);
+        assert!(!buffer.contains(2));
+        assert!(buffer.contains(1));
+        assert!(buffer.contains(3));
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        let config = crate::config::Config::default();
+        let mut sm = StateMachine::new(config);
+
+        assert_eq!(sm.state(), State::Idle);
+
+        sm.set_state(State::Decide);
+        assert_eq!(sm.state(), State::Decide);
+        assert!(sm.buffer.is_empty());
+
+        sm.set_state(State::Shift);
+        assert_eq!(sm.state(), State::Shift);
+    }
+
+    #[test]
+    fn test_key_map_no_mapping() {
         let config = crate::config::Config::default();
         let sm = StateMachine::new(config);
 
@@ -280,6 +1730,7 @@ mod tests {
         let config = crate::config::Config {
             keyboard: String::new(),
             keys_map: vec![[30, 105, 0]], // A -> F9
+            ..Default::default()
         };
         let sm = StateMachine::new(config);
 
@@ -293,6 +1744,7 @@ mod tests {
         let config = crate::config::Config {
             keyboard: String::new(),
             keys_map: vec![[104, 0, 109]], // PageUp -> Pause
+            ..Default::default()
         };
         let sm = StateMachine::new(config);
 
@@ -306,6 +1758,7 @@ mod tests {
         let config = crate::config::Config {
             keyboard: String::new(),
             keys_map: vec![[57, 0, 125]], // Space -> Fn+Space = Menu
+            ..Default::default()
         };
         let sm = StateMachine::new(config);
 
@@ -320,4 +1773,1443 @@ mod tests {
         assert!(config.keyboard.is_empty());
         assert!(config.keys_map.is_empty());
     }
+
+    #[test]
+    fn test_decide_timeout_flushes_mapped() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        assert_eq!(sm.state(), State::Decide);
+        assert!(sink.events.is_empty());
+
+        let later = now + Duration::from_millis(DECIDE_TIMEOUT_MS);
+        assert_eq!(sm.poll_timeout(&mut sink, later).unwrap(), State::Shift);
+        assert_eq!(sink.events, vec![(105, 1)]);
+    }
+
+    #[test]
+    fn test_space_tap_in_decide() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sm.state(), State::Idle);
+        assert_eq!(sink.events, vec![(KEY_SPACE, 1), (KEY_SPACE, 0), (36, 1)]);
+    }
+
+    #[test]
+    fn test_instant_key_first() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        let state = sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        assert_eq!(state, State::Shift);
+        assert_eq!(sink.events, vec![(108, 1)]);
+
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sm.state(), State::Idle);
+        assert_eq!(sink.events, vec![(108, 1), (105, 1), (108, 0), (105, 0)]);
+    }
+
+    #[test]
+    fn test_instant_key_after_buffered_key() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        assert_eq!(sm.state(), State::Decide);
+
+        let state = sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        assert_eq!(state, State::Shift);
+        assert_eq!(sink.events, vec![(105, 1), (108, 1)]);
+    }
+
+    #[test]
+    fn test_layer_combo_single_member_passes_through() {
+        let mut config = nav_config();
+        config.layer_key = crate::config::LayerKey::Combo(vec![56, 100]);
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, 56, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        sm.handle_key(&mut sink, 56, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        assert_eq!(sm.state(), State::Idle);
+        assert_eq!(
+            sink.events,
+            vec![(56, 1), (36, 1), (36, 0), (56, 0), (KEY_SPACE, 1)]
+        );
+    }
+
+    #[test]
+    fn test_layer_combo_activation() {
+        let mut config = nav_config();
+        config.layer_key = crate::config::LayerKey::Combo(vec![56, 100]);
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, 56, 1, now).unwrap();
+        let state = sm.handle_key(&mut sink, 100, 1, now).unwrap();
+        assert_eq!(state, State::Decide);
+        // The first member never went out, so there is no Alt tap to open a menu.
+        assert!(sink.events.is_empty());
+
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 100, 0, now).unwrap();
+        assert_eq!(sm.state(), State::Idle);
+        sm.handle_key(&mut sink, 56, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(108, 1), (108, 0)]);
+    }
+
+    #[test]
+    fn test_layer_combo_member_held_back() {
+        let mut config = nav_config();
+        config.layer_key = crate::config::LayerKey::Combo(vec![56, 100]);
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        // Tapped alone, it is typed on release.
+        sm.handle_key(&mut sink, 56, 1, now).unwrap();
+        assert!(sink.events.is_empty());
+        sm.handle_key(&mut sink, 56, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(56, 1), (56, 0)]);
+
+        // Held past the timeout, it goes out, and is taken back if the combo completes.
+        sink.events.clear();
+        sm.handle_key(&mut sink, 56, 1, now).unwrap();
+        let later = now + Duration::from_millis(DECIDE_TIMEOUT_MS);
+        assert_eq!(sm.deadline(), Some(later));
+        sm.poll_timeout(&mut sink, later).unwrap();
+        assert_eq!(sink.events, vec![(56, 1)]);
+        sm.handle_key(&mut sink, 100, 1, later).unwrap();
+        assert_eq!(sm.state(), State::Decide);
+        assert_eq!(sink.events, vec![(56, 1), (56, 0)]);
+    }
+
+    #[test]
+    fn test_resync_releases_dropped_keys() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, 30, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        assert_eq!(sm.state(), State::Shift);
+        sink.events.clear();
+
+        // Space and K were released while events were dropped, L went down.
+        let state = sm.resync(&mut sink, &[30, 38], now).unwrap();
+        assert_eq!(state, State::Idle);
+        assert_eq!(sm.stats.syn_dropped, 1);
+        assert_eq!(sink.events, vec![(108, 0), (38, 1)]);
+    }
+
+    #[test]
+    fn test_resync_resets_decide() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        assert_eq!(sm.state(), State::Decide);
+
+        let state = sm.resync(&mut sink, &[36], now).unwrap();
+        assert_eq!(state, State::Idle);
+        assert_eq!(sink.events, vec![(KEY_SPACE, 1), (KEY_SPACE, 0), (36, 1)]);
+
+        assert_eq!(sm.resync(&mut sink, &[36], now).unwrap(), State::Idle);
+        assert_eq!(sink.events.len(), 3);
+    }
+
+    #[test]
+    fn test_base_map_only_without_modifiers() {
+        let mut config = nav_config();
+        config.base_map = vec![crate::config::BaseMapping {
+            key: 2,
+            to: 2,
+            ext: 42,
+            when: Vec::new(),
+        }]; // 1 -> Shift+1
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, 2, 1, now).unwrap();
+        sm.handle_key(&mut sink, 2, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(42, 1), (2, 1), (2, 0), (42, 0)]);
+        sink.events.clear();
+
+        sm.handle_key(&mut sink, 54, 1, now).unwrap();
+        sm.handle_key(&mut sink, 2, 1, now).unwrap();
+        sm.handle_key(&mut sink, 2, 0, now).unwrap();
+        sm.handle_key(&mut sink, 54, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(54, 1), (2, 1), (2, 0), (54, 0)]);
+    }
+
+    #[test]
+    fn test_base_map_with_modifier() {
+        let mut config = nav_config();
+        config.base_map = vec![crate::config::BaseMapping {
+            key: 36,
+            to: 108,
+            ext: 0,
+            when: vec![Modifier::Ctrl],
+        }];
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        sm.handle_key(&mut sink, 29, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 29, 0, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        assert_eq!(
+            sink.events,
+            vec![(36, 1), (36, 0), (29, 1), (108, 1), (29, 0), (108, 0)]
+        );
+    }
+
+    #[test]
+    fn test_base_map_released_across_layer() {
+        let mut config = nav_config();
+        config.base_map = vec![crate::config::BaseMapping {
+            key: 58,
+            to: 1,
+            ext: 0,
+            when: Vec::new(),
+        }]; // Caps Lock -> Esc
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        // Released in Decide.
+        sm.handle_key(&mut sink, 58, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 58, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(
+            sink.events,
+            vec![(1, 1), (1, 0), (KEY_SPACE, 1), (KEY_SPACE, 0)]
+        );
+        sink.events.clear();
+
+        // Released in Shift.
+        sm.handle_key(&mut sink, 58, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.poll_timeout(&mut sink, now + Duration::from_millis(DECIDE_TIMEOUT_MS))
+            .unwrap();
+        assert_eq!(sm.state(), State::Shift);
+        sm.handle_key(&mut sink, 58, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(1, 1), (108, 1), (1, 0)]);
+        assert!(sm.base_active.is_empty());
+    }
+
+    #[test]
+    fn test_neutralize_modifiers_across_layer() {
+        let mut config = nav_config();
+        config.neutralize_modifiers = true;
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, 42, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        sm.handle_key(&mut sink, 42, 0, now).unwrap();
+        assert_eq!(
+            sink.events,
+            vec![(42, 1), (42, 0), (108, 1), (108, 0), (42, 1), (42, 0)]
+        );
+    }
+
+    #[test]
+    fn test_neutralized_modifier_released_in_layer() {
+        let mut config = nav_config();
+        config.neutralize_modifiers = true;
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, 42, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 42, 0, now).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(42, 1), (42, 0), (108, 1), (108, 0)]);
+    }
+
+    #[test]
+    fn test_hold_tap_per_key_hold_ms() {
+        let mut config = nav_config();
+        config.hold_tap = vec![
+            crate::config::HoldTap {
+                key: 30,
+                hold: 42,
+                hold_ms: Some(150),
+            }, // A, LShift
+            crate::config::HoldTap {
+                key: 31,
+                hold: 29,
+                hold_ms: Some(250),
+            }, // S, LCtrl
+        ];
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        // A released just before its threshold taps.
+        sm.handle_key(&mut sink, 30, 1, t0).unwrap();
+        assert_eq!(sm.deadline(), Some(t0 + ms(150)));
+        sm.handle_key(&mut sink, 30, 0, t0 + ms(149)).unwrap();
+        assert_eq!(sink.events, vec![(30, 1), (30, 0)]);
+        sink.events.clear();
+
+        // A held to its threshold holds.
+        sm.handle_key(&mut sink, 30, 1, t0).unwrap();
+        sm.poll_timeout(&mut sink, t0 + ms(150)).unwrap();
+        sm.handle_key(&mut sink, 30, 0, t0 + ms(300)).unwrap();
+        assert_eq!(sink.events, vec![(42, 1), (42, 0)]);
+        sink.events.clear();
+
+        // S is still a tap where A would already hold.
+        sm.handle_key(&mut sink, 31, 1, t0).unwrap();
+        sm.poll_timeout(&mut sink, t0 + ms(200)).unwrap();
+        assert!(sink.events.is_empty());
+        sm.handle_key(&mut sink, 31, 0, t0 + ms(249)).unwrap();
+        assert_eq!(sink.events, vec![(31, 1), (31, 0)]);
+        sink.events.clear();
+
+        sm.handle_key(&mut sink, 31, 1, t0).unwrap();
+        sm.poll_timeout(&mut sink, t0 + ms(250)).unwrap();
+        assert_eq!(sink.events, vec![(29, 1)]);
+    }
+
+    #[test]
+    fn test_hold_tap_queues_other_keys() {
+        let mut config = nav_config();
+        config.hold_tap = vec![crate::config::HoldTap {
+            key: 30,
+            hold: 42,
+            hold_ms: None,
+        }];
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+
+        sm.handle_key(&mut sink, 30, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 48, 1, t0).unwrap();
+        assert!(sink.events.is_empty());
+
+        let later = t0 + Duration::from_millis(DECIDE_TIMEOUT_MS);
+        sm.poll_timeout(&mut sink, later).unwrap();
+        sm.handle_key(&mut sink, 48, 0, later).unwrap();
+        sm.handle_key(&mut sink, 30, 0, later).unwrap();
+        assert_eq!(sink.events, vec![(42, 1), (48, 1), (48, 0), (42, 0)]);
+    }
+
+    #[test]
+    fn test_reconcile_releases_ghost_keys() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 38, 1, now).unwrap();
+        assert_eq!(sm.state(), State::Shift);
+        sink.events.clear();
+
+        // L's release was lost, Space and K are still down.
+        let state = sm.reconcile(&mut sink, &[KEY_SPACE, 37], now).unwrap();
+        assert_eq!(state, State::Shift);
+        assert_eq!(sink.events, vec![(106, 0)]);
+        assert_eq!(sm.stats.reconciled, 1);
+
+        sm.reconcile(&mut sink, &[KEY_SPACE, 37], now).unwrap();
+        assert_eq!(sink.events.len(), 1);
+    }
+
+    fn leader_config() -> crate::config::Config {
+        let mut config = nav_config();
+        config.leader = vec![
+            crate::config::LeaderSequence {
+                keys: vec![34, 34],
+                send: vec![29, 102],
+            }, // G G -> Ctrl+Home
+            crate::config::LeaderSequence {
+                keys: vec![34, 48],
+                send: vec![107],
+            }, // G B -> End
+        ];
+        config
+    }
+
+    #[test]
+    fn test_leader_sequence_complete() {
+        let mut sm = StateMachine::new(leader_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 34, 1, now).unwrap();
+        assert_eq!(sm.state(), State::Shift);
+        sm.handle_key(&mut sink, 34, 0, now).unwrap();
+        assert!(sink.events.is_empty());
+        assert!(sm.deadline().is_some());
+
+        sm.handle_key(&mut sink, 34, 1, now).unwrap();
+        sm.handle_key(&mut sink, 34, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(29, 1), (102, 1), (102, 0), (29, 0)]);
+        assert_eq!(sm.deadline(), None);
+    }
+
+    #[test]
+    fn test_leader_sequence_partial_times_out() {
+        let mut sm = StateMachine::new(leader_config());
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 34, 1, t0).unwrap();
+        assert_eq!(
+            sm.deadline(),
+            Some(t0 + Duration::from_millis(LEADER_TIMEOUT_MS))
+        );
+
+        let later = t0 + Duration::from_millis(LEADER_TIMEOUT_MS);
+        sm.poll_timeout(&mut sink, later).unwrap();
+        assert_eq!(sm.deadline(), None);
+        // A second G after the timeout starts over instead of completing G G.
+        sm.handle_key(&mut sink, 34, 0, later).unwrap();
+        sm.handle_key(&mut sink, 34, 1, later).unwrap();
+        sm.handle_key(&mut sink, 34, 0, later).unwrap();
+        assert!(sink.events.is_empty());
+    }
+
+    #[test]
+    fn test_leader_sequence_aborted() {
+        let mut sm = StateMachine::new(leader_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 34, 1, now).unwrap();
+        sm.handle_key(&mut sink, 34, 0, now).unwrap();
+        // J is not part of any G sequence, it aborts and is mapped as usual.
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(105, 1), (105, 0)]);
+        assert_eq!(sm.deadline(), None);
+
+        // Letting go of the layer key also aborts.
+        sink.events.clear();
+        sm.handle_key(&mut sink, 34, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        sm.handle_key(&mut sink, 34, 0, now).unwrap();
+        assert!(sink.events.is_empty());
+        assert_eq!(sm.deadline(), None);
+    }
+
+    #[test]
+    fn test_modifiers_first_on_timeout() {
+        let mut config = nav_config();
+        config.keys_map.push([30, 29, 0]); // A -> LCtrl
+        config.modifiers_first = true;
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 36, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 30, 1, t0).unwrap();
+        sm.poll_timeout(&mut sink, t0 + Duration::from_millis(DECIDE_TIMEOUT_MS))
+            .unwrap();
+        assert_eq!(sink.events, vec![(29, 1), (105, 1)]);
+    }
+
+    #[test]
+    fn test_mapping_above_255() {
+        let mut config = nav_config();
+        config.keys_map.push([48, 0x110, 0]); // B -> BTN_LEFT
+        assert!(output_codes(&config).contains(&0x110));
+
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 48, 1, now).unwrap();
+        sm.handle_key(&mut sink, 48, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(0x110, 1), (0x110, 0)]);
+    }
+
+    #[test]
+    fn test_set_mappings() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.set_mappings(&mut sink, vec![[37, 103, 0]], false)
+            .unwrap(); // K -> Up
+        assert_eq!(sink.events, vec![(108, 1), (108, 0)]);
+        assert_eq!(sm.state(), State::Idle);
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        sink.events.clear();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(103, 1), (103, 0)]);
+    }
+
+    #[test]
+    fn test_grab_grace_drops_events() {
+        let mut config = nav_config();
+        config.grab_grace_ms = 50;
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+
+        sm.start_grace(t0);
+        sm.handle_key(&mut sink, 30, 1, t0 + Duration::from_millis(10))
+            .unwrap();
+        sm.handle_key(&mut sink, 30, 0, t0 + Duration::from_millis(49))
+            .unwrap();
+        assert!(sink.events.is_empty());
+
+        sm.handle_key(&mut sink, 30, 1, t0 + Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(sink.events, vec![(30, 1)]);
+    }
+
+    #[test]
+    fn test_exec_action() {
+        let mut config = nav_config();
+        config.actions = vec![crate::config::ActionMapping {
+            key: 28,
+            action: Action::Exec("alacritty".to_string()),
+        }];
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 28, 1, now).unwrap();
+        assert_eq!(sm.state(), State::Shift);
+        sm.handle_key(&mut sink, 28, 2, now).unwrap();
+        sm.handle_key(&mut sink, 28, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sink.commands, vec!["alacritty"]);
+        assert!(sink.events.is_empty());
+    }
+
+    fn timed_machine() -> StateMachine {
+        let mut config = nav_config();
+        config.actions = vec![crate::config::ActionMapping {
+            key: 28,
+            action: Action::Timed(TimedPress {
+                key: 57,
+                hold_ms: 80,
+            }),
+        }];
+        StateMachine::new(config)
+    }
+
+    #[test]
+    fn test_timed_action() {
+        let mut sm = timed_machine();
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        // Let go of early, the key stays down for its full time.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 28, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 28, 0, t0 + ms(10)).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, t0 + ms(20)).unwrap();
+        assert_eq!(sink.events, vec![(57, 1)]);
+        assert_eq!(sm.deadline(), Some(t0 + ms(80)));
+        sm.poll_timeout(&mut sink, t0 + ms(79)).unwrap();
+        assert_eq!(sink.events, vec![(57, 1)]);
+        sm.poll_timeout(&mut sink, t0 + ms(80)).unwrap();
+        assert_eq!(sink.events, vec![(57, 1), (57, 0)]);
+        assert_eq!(sm.deadline(), None);
+    }
+
+    #[test]
+    fn test_timed_action_pressed_again() {
+        let mut sm = timed_machine();
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 28, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 28, 0, t0 + ms(10)).unwrap();
+        // Pressed again before the release, it starts over.
+        sm.handle_key(&mut sink, 28, 1, t0 + ms(50)).unwrap();
+        assert_eq!(sink.events, vec![(57, 1), (57, 0), (57, 1)]);
+        assert_eq!(sm.deadline(), Some(t0 + ms(130)));
+
+        // Held longer than its time, it is still released on time.
+        sm.poll_timeout(&mut sink, t0 + ms(130)).unwrap();
+        sm.handle_key(&mut sink, 28, 0, t0 + ms(200)).unwrap();
+        assert_eq!(sink.events, vec![(57, 1), (57, 0), (57, 1), (57, 0)]);
+    }
+
+    #[test]
+    fn test_key_value_parse() {
+        assert_eq!(KeyValue::parse(0), Some(KeyValue::Release));
+        assert_eq!(KeyValue::parse(1), Some(KeyValue::Press));
+        assert_eq!(KeyValue::parse(2), Some(KeyValue::Repeat));
+        assert_eq!(KeyValue::parse(3), None);
+        assert_eq!(KeyValue::parse(-1), None);
+    }
+
+    #[test]
+    fn test_unknown_value_forwarded_verbatim() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 3, now).unwrap();
+        // Not taken for a release: 36 is still held.
+        assert_eq!(sm.held, vec![36]);
+        // Nor does it end the layer when sent by the layer key.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 3, now).unwrap();
+        assert_eq!(sm.state(), State::Decide);
+        assert_eq!(sink.events, vec![(36, 1), (36, 3), (KEY_SPACE, 3)]);
+    }
+
+    fn caps_word_machine() -> StateMachine {
+        let mut config = nav_config();
+        config.actions = vec![crate::config::ActionMapping {
+            key: 58,
+            action: Action::CapsWord(true),
+        }];
+        StateMachine::new(config)
+    }
+
+    fn tap(sm: &mut StateMachine, sink: &mut RecordingSink, code: u16, now: Instant) {
+        sm.handle_key(sink, code, 1, now).unwrap();
+        sm.handle_key(sink, code, 0, now).unwrap();
+    }
+
+    #[test]
+    fn test_caps_word_arms_from_layer() {
+        let mut sm = caps_word_machine();
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        tap(&mut sm, &mut sink, 58, now);
+        assert!(sm.caps_word);
+        // Still armed after the layer ends, and the Caps Lock tap never reaches the output.
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert!(sm.caps_word);
+        assert!(sink.events.is_empty());
+    }
+
+    #[test]
+    fn test_caps_word_shifts_letters() {
+        let mut sm = caps_word_machine();
+        sm.caps_word = true;
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        // h i 2 x
+        tap(&mut sm, &mut sink, 35, now);
+        tap(&mut sm, &mut sink, 23, now);
+        tap(&mut sm, &mut sink, 3, now);
+        tap(&mut sm, &mut sink, 45, now);
+        assert_eq!(
+            sink.events,
+            vec![
+                (KEY_LEFTSHIFT, 1),
+                (35, 1),
+                (35, 0),
+                (23, 1),
+                (23, 0),
+                (KEY_LEFTSHIFT, 0),
+                (3, 1),
+                (3, 0),
+                (KEY_LEFTSHIFT, 1),
+                (45, 1),
+                (45, 0),
+            ]
+        );
+        assert!(sm.caps_word);
+    }
+
+    #[test]
+    fn test_caps_word_ends_on_space_and_punctuation() {
+        let mut sm = caps_word_machine();
+        sm.caps_word = true;
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        tap(&mut sm, &mut sink, 35, now);
+        tap(&mut sm, &mut sink, KEY_SPACE, now);
+        tap(&mut sm, &mut sink, 23, now);
+        assert_eq!(
+            sink.events,
+            vec![
+                (KEY_LEFTSHIFT, 1),
+                (35, 1),
+                (35, 0),
+                (KEY_LEFTSHIFT, 0),
+                (KEY_SPACE, 1),
+                (KEY_SPACE, 0),
+                (23, 1),
+                (23, 0),
+            ]
+        );
+        assert!(!sm.caps_word);
+
+        sm.caps_word = true;
+        sink.events.clear();
+        tap(&mut sm, &mut sink, 35, now);
+        tap(&mut sm, &mut sink, 52, now);
+        assert_eq!(sink.events[3], (KEY_LEFTSHIFT, 0));
+        assert_eq!(sink.events[4], (52, 1));
+        assert!(!sm.caps_word);
+    }
+
+    #[test]
+    fn test_own_device_not_listed() {
+        let devices = vec![
+            InputDeviceInfo {
+                path: "/dev/input/event3".to_string(),
+                name: "AT Translated Set 2 keyboard".to_string(),
+                stable_path: None,
+            },
+            InputDeviceInfo {
+                path: "/dev/input/event20".to_string(),
+                name: VIRTUAL_DEVICE_NAME.to_string(),
+                stable_path: None,
+            },
+        ];
+        let devices = without_own_device(devices);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].path, "/dev/input/event3");
+    }
+
+    #[test]
+    fn test_virtual_device_reason() {
+        assert_eq!(
+            virtual_device_reason(BusType::BUS_I8042, "AT Translated Set 2 keyboard"),
+            None
+        );
+        assert_eq!(virtual_device_reason(BusType::BUS_USB, "Keychron K2"), None);
+        assert!(virtual_device_reason(BusType::BUS_VIRTUAL, "ydotoold keyboard").is_some());
+        assert!(virtual_device_reason(BusType::BUS_USB, VIRTUAL_DEVICE_NAME).is_some());
+        assert!(virtual_device_reason(BusType::BUS_USB, "Virtual core keyboard").is_some());
+    }
+
+    #[test]
+    fn test_registration_warnings() {
+        // Everything registered.
+        assert!(registration_warnings(&[30, 36, 105], &[30, 36, 105], &[105]).is_empty());
+
+        // 0x110 (BTN_LEFT) and 31 were dropped, and the former is a mapping target.
+        let warnings =
+            registration_warnings(&[30, 31, 36, 105, 0x110], &[30, 36, 105], &[105, 0x110]);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("2 of 5 keys"), "{}", warnings[0]);
+        assert!(warnings[1].contains("272"), "{}", warnings[1]);
+
+        let warnings = registration_warnings(&[30, 36], &[], &[]);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("no keys"));
+    }
+
+    #[test]
+    fn test_stable_device_paths() {
+        let dir = std::env::temp_dir().join(format!("spacefn-test-{}-links", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for sub in ["by-id", "by-path"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        std::fs::write(dir.join("event3"), "").unwrap();
+        std::fs::write(dir.join("event4"), "").unwrap();
+        let link = |target: &str, name: &str| {
+            std::os::unix::fs::symlink(Path::new("..").join(target), dir.join(name)).unwrap();
+        };
+        link("event3", "by-id/usb-kbd-event-kbd");
+        link("event3", "by-path/platform-i8042-event-kbd");
+        link("event4", "by-path/pci-usb-event-kbd");
+        let event3 = std::fs::canonicalize(dir.join("event3")).unwrap();
+        let event4 = std::fs::canonicalize(dir.join("event4")).unwrap();
+
+        let by_id = dir.join("by-id/usb-kbd-event-kbd").display().to_string();
+        assert_eq!(resolve_device_path(&by_id), event3.display().to_string());
+        let missing = dir.join("by-id/gone").display().to_string();
+        assert_eq!(resolve_device_path(&missing), missing);
+
+        // by-id wins over by-path for the same device.
+        let links = stable_links(&[&dir.join("by-id"), &dir.join("by-path"), &dir.join("none")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[&event3], by_id);
+        assert_eq!(
+            links[&event4],
+            dir.join("by-path/pci-usb-event-kbd").display().to_string()
+        );
+    }
+
+    #[test]
+    fn test_find_output_device() {
+        let devices = vec![
+            InputDeviceInfo {
+                path: "/dev/input/event3".to_string(),
+                name: "AT Translated Set 2 keyboard".to_string(),
+                stable_path: None,
+            },
+            InputDeviceInfo {
+                path: "/dev/input/event21".to_string(),
+                name: "pipeline keyboard".to_string(),
+                stable_path: None,
+            },
+        ];
+        assert_eq!(
+            find_output_device(&devices, "pipeline keyboard", "/dev/input/event3"),
+            Some("/dev/input/event21")
+        );
+        assert_eq!(
+            find_output_device(&devices, "missing", "/dev/input/event3"),
+            None
+        );
+        // Never the keyboard we are reading.
+        assert_eq!(
+            find_output_device(
+                &devices,
+                "AT Translated Set 2 keyboard",
+                "/dev/input/event3"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_min_layer_hold_types_early_keys() {
+        let mut config = nav_config();
+        config.min_layer_hold_ms = 80;
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        // J right after Space is typed as Space then J.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 36, 1, t0 + ms(30)).unwrap();
+        assert_eq!(sm.state(), State::Idle);
+        sm.handle_key(&mut sink, KEY_SPACE, 2, t0 + ms(40)).unwrap();
+        sm.handle_key(&mut sink, 36, 0, t0 + ms(50)).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, t0 + ms(60)).unwrap();
+        assert_eq!(
+            sink.events,
+            vec![(KEY_SPACE, 1), (KEY_SPACE, 0), (36, 1), (36, 0)]
+        );
+        sink.events.clear();
+
+        // Once Space has been held long enough, J is mapped.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0 + ms(100))
+            .unwrap();
+        sm.handle_key(&mut sink, 36, 1, t0 + ms(180)).unwrap();
+        sm.handle_key(&mut sink, 36, 0, t0 + ms(190)).unwrap();
+        assert_eq!(sink.events, vec![(105, 1), (105, 0)]);
+    }
+
+    #[test]
+    fn test_extended_only_mapping_tracked_in_shift() {
+        let mut config = nav_config();
+        config.keys_map.push([104, 0, 109]);
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        assert_eq!(sm.state(), State::Shift);
+        sink.events.clear();
+
+        sm.handle_key(&mut sink, 104, 1, now).unwrap();
+        assert!(sm.buffer.contains(104));
+        // Letting go of the layer first releases both keys of the mapping.
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(109, 1), (104, 1), (104, 0), (109, 0)]);
+    }
+
+    fn repeat_machine(repeat: RepeatMode) -> StateMachine {
+        let mut config = nav_config();
+        config.repeat = vec![crate::config::RepeatMapping { key: 37, repeat }];
+        StateMachine::new(config)
+    }
+
+    /// Holds K, an instant key, in the layer with two keyboard repeats.
+    fn hold_k(sm: &mut StateMachine, sink: &mut RecordingSink, t0: Instant) {
+        let ms = Duration::from_millis;
+        sm.handle_key(sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(sink, 37, 1, t0 + ms(10)).unwrap();
+        sm.handle_key(sink, 37, 2, t0 + ms(300)).unwrap();
+        sm.handle_key(sink, 37, 2, t0 + ms(330)).unwrap();
+    }
+
+    #[test]
+    fn test_repeat_held() {
+        let mut sm = repeat_machine(RepeatMode::Held);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+
+        hold_k(&mut sm, &mut sink, t0);
+        sm.handle_key(&mut sink, 37, 0, t0 + Duration::from_millis(340))
+            .unwrap();
+        assert_eq!(sink.events, vec![(108, 1), (108, 2), (108, 2), (108, 0)]);
+        assert_eq!(sm.deadline(), None);
+    }
+
+    #[test]
+    fn test_repeat_one_shot() {
+        let mut sm = repeat_machine(RepeatMode::OneShot);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+
+        hold_k(&mut sm, &mut sink, t0);
+        sm.handle_key(&mut sink, 37, 0, t0 + Duration::from_millis(340))
+            .unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, t0 + Duration::from_millis(350))
+            .unwrap();
+        assert_eq!(sink.events, vec![(108, 1), (108, 0)]);
+    }
+
+    #[test]
+    fn test_repeat_one_shot_flushed_from_decide() {
+        let mut config = nav_config();
+        config.repeat = vec![crate::config::RepeatMapping {
+            key: 36,
+            repeat: RepeatMode::OneShot,
+        }];
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        // J is buffered in Decide, and its release commits to Shift.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(105, 1), (105, 0)]);
+        assert!(sm.swallowed.is_empty());
+    }
+
+    #[test]
+    fn test_repeat_auto_repeat() {
+        let mut sm = repeat_machine(RepeatMode::AutoRepeat);
+        sm.config.auto_repeat_delay_ms = Some(500);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        // The keyboard's repeats are ignored, ours start after the delay.
+        hold_k(&mut sm, &mut sink, t0);
+        assert_eq!(sink.events, vec![(108, 1)]);
+        assert_eq!(sm.deadline(), Some(t0 + ms(510)));
+        sm.poll_timeout(&mut sink, t0 + ms(510)).unwrap();
+        sm.poll_timeout(&mut sink, t0 + ms(510 + AUTO_REPEAT_INTERVAL_MS))
+            .unwrap();
+        sm.handle_key(&mut sink, 37, 0, t0 + ms(560)).unwrap();
+        assert_eq!(sink.events, vec![(108, 1), (108, 2), (108, 2), (108, 0)]);
+        assert_eq!(sm.deadline(), None);
+        sm.poll_timeout(&mut sink, t0 + ms(1000)).unwrap();
+        assert_eq!(sink.events.len(), 4);
+    }
+
+    /// Holds J in the Fn layer, then switches to a config mapping J to Right instead.
+    fn switch_while_held(policy: SwitchPolicy) -> (StateMachine, RecordingSink) {
+        let mut config = nav_config();
+        config.switch_while_held = policy;
+        let mut sm = StateMachine::new(config.clone());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        config.keys_map = vec![[36, 106, 0]];
+        sm.load_config(&mut sink, config).unwrap();
+        (sm, sink)
+    }
+
+    #[test]
+    fn test_switch_while_held_release() {
+        let (mut sm, mut sink) = switch_while_held(SwitchPolicy::Release);
+        let now = Instant::now();
+
+        // Left is released by the switch, not stuck until J comes up.
+        assert_eq!(sink.events, vec![(108, 1), (105, 1), (108, 0), (105, 0)]);
+        assert_eq!(sm.state(), State::Idle);
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        // Only J's own release gets through, Left is not pressed again.
+        assert_eq!(sink.events[4..], [(36, 0)]);
+    }
+
+    #[test]
+    fn test_switch_while_held_defer() {
+        let (mut sm, mut sink) = switch_while_held(SwitchPolicy::Defer);
+        let now = Instant::now();
+
+        // J still releases what it pressed under the old config.
+        assert_eq!(sm.state(), State::Shift);
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(108, 1), (105, 1), (108, 0), (105, 0)]);
+        sink.events.clear();
+
+        // The new config is in use from the next key.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(106, 1), (106, 0)]);
+        assert_eq!(sm.previous_config().unwrap().keys_map[0], [36, 105, 0]);
+    }
+
+    #[test]
+    fn test_reload_while_held() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        let mut config = nav_config();
+        config.keys_map.retain(|m| m[0] != 37);
+        sm.reload_config(&mut sink, config).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+
+        // Down is let go by the reload instead of sticking, as with load_config only K's
+        // own release gets through afterwards.
+        assert_eq!(sink.events, vec![(108, 1), (108, 0), (37, 0)]);
+        // A reload is not something to revert to.
+        assert!(sm.previous_config().is_none());
+    }
+
+    #[test]
+    fn test_describe_mapping() {
+        assert_eq!(
+            describe_mapping(36, 105, None),
+            "KEY_J (36) -> KEY_LEFT (105)"
+        );
+        assert_eq!(
+            describe_mapping(35, 14, Some(29)),
+            "KEY_H (35) -> KEY_BACKSPACE (14) + KEY_LEFTCTRL (29)"
+        );
+    }
+
+    #[test]
+    fn test_numpad_numlock() {
+        let mut config = nav_config();
+        config.apply_numpad_preset();
+        // Registered even on keyboards without NumLock.
+        assert!(output_codes(&config).contains(&KEY_NUMLOCK));
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        // NumLock goes on before the first digit and off again with the layer.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 22, 1, now).unwrap();
+        sm.handle_key(&mut sink, 22, 0, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(
+            sink.events,
+            vec![
+                (KEY_NUMLOCK, 1),
+                (KEY_NUMLOCK, 0),
+                (71, 1),
+                (71, 0),
+                (75, 1),
+                (75, 0),
+                (KEY_NUMLOCK, 1),
+                (KEY_NUMLOCK, 0),
+            ]
+        );
+
+        // Already on, it is left alone.
+        sink.events.clear();
+        sm.set_numlock(true);
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 23, 1, now).unwrap();
+        sm.handle_key(&mut sink, 23, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(72, 1), (72, 0)]);
+    }
+
+    #[test]
+    fn test_numpad_numlock_toggled_by_user() {
+        let mut config = nav_config();
+        config.apply_numpad_preset();
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 24, 1, now).unwrap();
+        sm.handle_key(&mut sink, 24, 0, now).unwrap();
+        // NumLock pressed in the layer is the user's choice, it is not undone.
+        sm.handle_key(&mut sink, KEY_NUMLOCK, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_NUMLOCK, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(
+            sink.events,
+            vec![
+                (KEY_NUMLOCK, 1),
+                (KEY_NUMLOCK, 0),
+                (73, 1),
+                (73, 0),
+                (KEY_NUMLOCK, 1),
+                (KEY_NUMLOCK, 0),
+            ]
+        );
+        assert!(!sm.numlock);
+    }
+
+    #[test]
+    fn test_reset_from_shift() {
+        let mut config = nav_config();
+        config.keys_map.push([104, 0, 109]);
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 104, 1, now).unwrap();
+        assert_eq!(sm.state(), State::Shift);
+        sink.events.clear();
+
+        sm.reset(&mut sink).unwrap();
+        assert_eq!(sm.state(), State::Idle);
+        assert!(sm.buffer.is_empty());
+        assert_eq!(sink.events, vec![(108, 0), (104, 0), (109, 0)]);
+        assert_eq!(sm.deadline(), None);
+    }
+
+    #[test]
+    fn test_flush_releases_passed_through_keys() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, 56, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sink.events.clear();
+
+        sm.flush(&mut sink).unwrap();
+        assert_eq!(
+            sink.events,
+            vec![
+                (108, 0),
+                (105, 0),
+                (56, 0),
+                (KEY_SPACE, 0),
+                (37, 0),
+                (36, 0)
+            ]
+        );
+        assert!(sm.held.is_empty());
+    }
+
+    #[test]
+    fn test_enable_mapping_while_held() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        // K is held unmapped in the layer when its mapping is switched back on.
+        sm.set_mapping_enabled(&mut sink, 37, false).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.poll_timeout(&mut sink, now + Duration::from_millis(DECIDE_TIMEOUT_MS))
+            .unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.set_mapping_enabled(&mut sink, 37, true).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(105, 1), (37, 1), (37, 0)]);
+
+        // And the other way round while it is held mapped.
+        sink.events.clear();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.set_mapping_enabled(&mut sink, 37, false).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(108, 1), (108, 0)]);
+    }
+
+    #[test]
+    fn test_set_mapping_enabled() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        assert!(sm.set_mapping_enabled(&mut sink, 50, false).is_err());
+
+        // K is held in the layer when its mapping is switched off.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.set_mapping_enabled(&mut sink, 37, false).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(108, 1), (108, 0)]);
+        sink.events.clear();
+
+        // Now it types itself in the layer, while other mappings still work.
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(37, 1), (37, 0), (105, 1), (105, 0)]);
+        sink.events.clear();
+
+        sm.set_mapping_enabled(&mut sink, 37, true).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(108, 1), (108, 0)]);
+    }
+
+    #[test]
+    fn test_typing_overlap_boundary() {
+        let mut config = nav_config();
+        config.typing_overlap_ms = 30;
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        // 29 ms after Space: an overlap while typing.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 36, 1, t0 + ms(29)).unwrap();
+        assert_eq!(sm.state(), State::Idle);
+        sm.handle_key(&mut sink, KEY_SPACE, 0, t0 + ms(40)).unwrap();
+        sm.handle_key(&mut sink, 36, 0, t0 + ms(50)).unwrap();
+        assert_eq!(
+            sink.events,
+            vec![(KEY_SPACE, 1), (KEY_SPACE, 0), (36, 1), (36, 0)]
+        );
+        sink.events.clear();
+
+        // 30 ms after Space: a chord.
+        let t1 = t0 + ms(100);
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t1).unwrap();
+        sm.handle_key(&mut sink, 36, 1, t1 + ms(30)).unwrap();
+        assert_eq!(sm.state(), State::Decide);
+        sm.handle_key(&mut sink, 36, 0, t1 + ms(40)).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, t1 + ms(50)).unwrap();
+        assert_eq!(sink.events, vec![(105, 1), (105, 0)]);
+    }
+
+    #[test]
+    fn test_typing_overlap_first_key_only() {
+        let mut config = nav_config();
+        config.typing_overlap_ms = 30;
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        // The first key came late enough, a second one soon after is part of the chord.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, 36, 1, t0 + ms(30)).unwrap();
+        sm.handle_key(&mut sink, 38, 1, t0 + ms(31)).unwrap();
+        assert_eq!(sm.state(), State::Decide);
+        sm.handle_key(&mut sink, 36, 0, t0 + ms(40)).unwrap();
+        assert_eq!(sink.events[..2], [(105, 1), (106, 1)]);
+    }
+
+    #[test]
+    fn test_key_row() {
+        assert_eq!(key_row(2), Row::Number); // 1
+        assert_eq!(key_row(14), Row::Number); // Backspace
+        assert_eq!(key_row(16), Row::Top); // Q
+        assert_eq!(key_row(43), Row::Top); // Backslash
+        assert_eq!(key_row(36), Row::Home); // J
+        assert_eq!(key_row(58), Row::Home); // Caps Lock
+        assert_eq!(key_row(44), Row::Bottom); // Z
+        assert_eq!(key_row(54), Row::Bottom); // Right Shift
+        assert_eq!(key_row(KEY_SPACE), Row::Other);
+        assert_eq!(key_row(1), Row::Other); // Esc
+        assert_eq!(key_row(105), Row::Other); // Left
+    }
+
+    fn ext_order_events(ext_order: ExtOrder) -> Vec<(u16, i32)> {
+        let mut config = nav_config();
+        config.keys_map.push([45, 46, 29]); // X -> Ctrl+C
+        config.ext_order = ext_order;
+        let mut sm = StateMachine::new(config);
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        sink.events.clear();
+        sm.handle_key(&mut sink, 45, 1, now).unwrap();
+        sm.handle_key(&mut sink, 45, 0, now).unwrap();
+        sink.events
+    }
+
+    #[test]
+    fn test_ext_order_before() {
+        assert_eq!(
+            ext_order_events(ExtOrder::Before),
+            vec![(29, 1), (46, 1), (46, 0), (29, 0)]
+        );
+    }
+
+    #[test]
+    fn test_ext_order_after() {
+        assert_eq!(
+            ext_order_events(ExtOrder::After),
+            vec![(46, 1), (29, 1), (29, 0), (46, 0)]
+        );
+    }
+
+    fn double_config() -> crate::config::Config {
+        let mut config = nav_config();
+        config.double_keys_map = vec![[36, 102, 0]]; // Space Space+J -> Home
+        config
+    }
+
+    #[test]
+    fn test_double_tap_layer() {
+        let mut sm = StateMachine::new(double_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert!(sink.events.is_empty());
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 0, now).unwrap();
+        // K has no double mapping and falls back to keys_map.
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(102, 1), (102, 0), (108, 1), (108, 0)]);
+
+        // Back to the normal layer afterwards.
+        sink.events.clear();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 36, 1, now).unwrap();
+        assert_eq!(sink.events, vec![(108, 1), (105, 1)]);
+    }
+
+    #[test]
+    fn test_double_tap_held_back_tap() {
+        let mut sm = StateMachine::new(double_config());
+        let mut sink = RecordingSink::default();
+        let t0 = Instant::now();
+
+        // The held back tap is typed once the window passes...
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, t0).unwrap();
+        assert_eq!(
+            sm.deadline(),
+            Some(t0 + Duration::from_millis(DECIDE_TIMEOUT_MS))
+        );
+        sm.poll_timeout(&mut sink, t0 + Duration::from_millis(DECIDE_TIMEOUT_MS))
+            .unwrap();
+        assert_eq!(sink.events, vec![(KEY_SPACE, 1), (KEY_SPACE, 0)]);
+        sink.events.clear();
+
+        // ...or before any other key.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, t0).unwrap();
+        sm.handle_key(&mut sink, 30, 1, t0).unwrap();
+        assert_eq!(sink.events, vec![(KEY_SPACE, 1), (KEY_SPACE, 0), (30, 1)]);
+        sink.events.clear();
+        sm.handle_key(&mut sink, 30, 0, t0).unwrap();
+
+        // Tapping twice types two spaces.
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, t0).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, t0).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, t0).unwrap();
+        assert_eq!(
+            sink.events,
+            vec![
+                (30, 0),
+                (KEY_SPACE, 1),
+                (KEY_SPACE, 0),
+                (KEY_SPACE, 1),
+                (KEY_SPACE, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_and_revert_config() {
+        let mut sm = StateMachine::new(nav_config());
+        let mut sink = RecordingSink::default();
+        let now = Instant::now();
+
+        let gaming = crate::config::Config {
+            keys_map: vec![[37, 103, 0]], // K -> Up
+            instant_keys: vec![37],
+            ..Default::default()
+        };
+        sm.load_config(&mut sink, gaming).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 0, now).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 0, now).unwrap();
+        assert_eq!(sink.events, vec![(103, 1), (103, 0)]);
+        sink.events.clear();
+
+        sm.revert_config(&mut sink).unwrap();
+        sm.handle_key(&mut sink, KEY_SPACE, 1, now).unwrap();
+        sm.handle_key(&mut sink, 37, 1, now).unwrap();
+        assert_eq!(sink.events, vec![(108, 1)]);
+
+        // Only one config was loaded.
+        assert!(sm.revert_config(&mut sink).is_err());
+    }
 }
@@ -0,0 +1,164 @@
+use crate::config::Config;
+use crate::core::InputDeviceInfo;
+use std::fmt::Write;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// An input device and the number of keys it reports, or why it could not be read.
+pub struct DeviceReport {
+    pub info: InputDeviceInfo,
+    pub keys: Result<usize, String>,
+}
+
+/// Lists the input devices with their key counts.
+pub fn probe_devices() -> Vec<DeviceReport> {
+    crate::core::list_input_devices()
+        .into_iter()
+        .map(|info| {
+            let keys = evdev::raw_stream::RawDevice::open(&info.path)
+                .map(|device| crate::core::supported_key_codes(&device).len())
+                .map_err(|e| e.to_string());
+            DeviceReport { info, keys }
+        })
+        .collect()
+}
+
+/// Builds the text of a diagnostics report. `key_events` are the recent keys, newest first.
+pub fn collect(config: &Config, devices: &[DeviceReport], key_events: &[String]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# spacefn-rs diagnostics");
+    let _ = writeln!(out, "version: {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(
+        out,
+        "features: ui={} logind={}",
+        cfg!(feature = "ui"),
+        cfg!(feature = "logind")
+    );
+
+    let _ = writeln!(out, "\n## Config");
+    match config.to_toml_string() {
+        Ok(content) => out.push_str(&content),
+        Err(e) => {
+            let _ = writeln!(out, "Failed to serialize the config: {}", e);
+        }
+    }
+    for warning in config.validate() {
+        let _ = writeln!(out, "warning: {}", warning);
+    }
+
+    let _ = writeln!(out, "\n## Devices");
+    if devices.is_empty() {
+        let _ = writeln!(out, "No input devices found");
+    }
+    for device in devices {
+        let keys = match &device.keys {
+            Ok(count) => format!("{} keys", count),
+            Err(e) => format!("not readable: {}", e),
+        };
+        let _ = writeln!(out, "{} ({}): {}", device.info.path, device.info.name, keys);
+    }
+
+    let _ = writeln!(out, "\n## Logs");
+    let _ = writeln!(
+        out,
+        "Logs go to stderr only, run with RUST_LOG=debug and attach the output."
+    );
+
+    let _ = writeln!(out, "\n## Recent keys");
+    if key_events.is_empty() {
+        let _ = writeln!(out, "No key events");
+    }
+    for event in key_events {
+        let _ = writeln!(out, "{}", event);
+    }
+    out
+}
+
+/// Writes `report` to a new file only the user can read and returns its path. The file
+/// goes in `$XDG_RUNTIME_DIR`, else the cache directory, since the report holds typed keys.
+pub fn write_report(report: &str) -> anyhow::Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::cache_dir()
+            .ok_or_else(|| anyhow::anyhow!("No runtime or cache directory for the report"))?
+            .join("spacefn"),
+    };
+    write_report_in(&dir, report)
+}
+
+fn write_report_in(dir: &Path, report: &str) -> anyhow::Result<PathBuf> {
+    std::fs::create_dir_all(dir)
+        .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", dir.display(), e))?;
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = dir.join(format!("spacefn-diagnostics-{}.txt", stamp));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(report.as_bytes()))
+        .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect() {
+        let config = Config {
+            keyboard: "/dev/input/event3".to_string(),
+            keys_map: vec![[36, 105, 0]],
+            ..Default::default()
+        };
+        let devices = vec![
+            DeviceReport {
+                info: InputDeviceInfo {
+                    path: "/dev/input/event3".to_string(),
+                    name: "AT Translated Set 2 keyboard".to_string(),
+                    stable_path: None,
+                },
+                keys: Ok(104),
+            },
+            DeviceReport {
+                info: InputDeviceInfo {
+                    path: "/dev/input/event5".to_string(),
+                    name: "Power Button".to_string(),
+                    stable_path: None,
+                },
+                keys: Err("Permission denied".to_string()),
+            },
+        ];
+        let report = collect(&config, &devices, &["↓ 036 J".to_string()]);
+
+        assert!(report.contains(env!("CARGO_PKG_VERSION")));
+        assert!(report.contains("keyboard = \"/dev/input/event3\""));
+        assert!(report.contains("/dev/input/event3 (AT Translated Set 2 keyboard): 104 keys"));
+        assert!(
+            report.contains("/dev/input/event5 (Power Button): not readable: Permission denied")
+        );
+        assert!(report.ends_with("## Recent keys\n↓ 036 J\n"));
+    }
+
+    #[test]
+    fn test_write_report_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("spacefn-test-{}-report", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = write_report_in(&dir, "↓ 036 J\n").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let content = std::fs::read_to_string(&path).unwrap();
+        // An existing file is never written through, e.g. a link planted at the same name.
+        let again = write_report_in(&dir, "");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(content, "↓ 036 J\n");
+        assert!(again.is_err() || again.unwrap() != path);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::Config;
+use crate::CoreCommand;
+use std::sync::mpsc;
+use std::time::Duration;
+
+const FOCUS_POLL_MS: u64 = 500;
+
+/// Prints the WM_CLASS of the active X11 window, used when no focus_command is set.
+const XPROP_COMMAND: &str =
+    "xprop -id \"$(xprop -root _NET_ACTIVE_WINDOW | awk '{print $NF}')\" WM_CLASS";
+
+/// The window classes in the output of the focus command: the quoted names of an xprop
+/// `WM_CLASS(STRING) = "instance", "Class"` line, else each non-empty line.
+pub fn parse_classes(output: &str) -> Vec<String> {
+    if let Some((_, names)) = output
+        .split_once("WM_CLASS")
+        .and_then(|(_, rest)| rest.split_once('='))
+    {
+        return names
+            .split(',')
+            .map(|name| name.trim().trim_matches('"').to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+    }
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether any class of the focused window is in `apps`, ignoring case.
+pub fn is_blocked(classes: &[String], apps: &[String]) -> bool {
+    classes
+        .iter()
+        .any(|class| apps.iter().any(|app| app.eq_ignore_ascii_case(class)))
+}
+
+/// Turns what is focused into pause commands, one each time the decision changes.
+#[derive(Debug, Default)]
+pub struct FocusPause {
+    paused: bool,
+}
+
+impl FocusPause {
+    pub fn update(&mut self, classes: &[String], apps: &[String]) -> Option<CoreCommand> {
+        let blocked = is_blocked(classes, apps);
+        if blocked == self.paused {
+            return None;
+        }
+        self.paused = blocked;
+        Some(CoreCommand::SetFocusPaused(blocked))
+    }
+}
+
+fn focused_classes(command: &str) -> anyhow::Result<Vec<String>> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_classes(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Polls the focused window while pause_for_apps is set, pausing remapping for those apps.
+/// The app list and command are read once, at startup.
+pub fn spawn_focus_watcher(config: &Config, cmd_tx: mpsc::Sender<CoreCommand>) {
+    if config.pause_for_apps.is_empty() {
+        return;
+    }
+    let apps = config.pause_for_apps.clone();
+    let command = if config.focus_command.is_empty() {
+        XPROP_COMMAND.to_string()
+    } else {
+        config.focus_command.clone()
+    };
+    std::thread::spawn(move || {
+        let mut focus = FocusPause::default();
+        let mut failing = false;
+        loop {
+            match focused_classes(&command) {
+                Ok(classes) => {
+                    failing = false;
+                    if let Some(command) = focus.update(&classes, &apps) {
+                        log::info!("Focused {:?}", classes);
+                        if cmd_tx.send(command).is_err() {
+                            break;
+                        }
+                    }
+                }
+                // Warn once per run of failures, e.g. with no X11 display; the last
+                // decision stands meanwhile.
+                Err(e) if !failing => {
+                    log::warn!("Focus watcher: {}", e);
+                    failing = true;
+                }
+                Err(e) => log::debug!("Focus watcher: {}", e),
+            }
+            std::thread::sleep(Duration::from_millis(FOCUS_POLL_MS));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_classes() {
+        assert_eq!(
+            parse_classes("WM_CLASS(STRING) = \"steam_app_730\", \"steam_app_730\"\n"),
+            strings(&["steam_app_730", "steam_app_730"])
+        );
+        assert_eq!(
+            parse_classes("org.remmina.Remmina\n"),
+            strings(&["org.remmina.Remmina"])
+        );
+        assert!(parse_classes("\n").is_empty());
+    }
+
+    #[test]
+    fn test_focus_pause() {
+        let apps = strings(&["steam_app_730", "org.remmina.Remmina"]);
+        let mut focus = FocusPause::default();
+
+        assert!(focus
+            .update(&strings(&["firefox", "Firefox"]), &apps)
+            .is_none());
+        assert!(matches!(
+            focus.update(&strings(&["steam_app_730"]), &apps),
+            Some(CoreCommand::SetFocusPaused(true))
+        ));
+        // Staying on a blocked app, or moving to another, sends nothing more.
+        assert!(focus
+            .update(&strings(&["org.remmina.remmina"]), &apps)
+            .is_none());
+        assert!(matches!(
+            focus.update(&strings(&["Alacritty"]), &apps),
+            Some(CoreCommand::SetFocusPaused(false))
+        ));
+        assert!(focus.update(&[], &apps).is_none());
+    }
+}
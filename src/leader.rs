@@ -0,0 +1,114 @@
+use crate::config::LeaderSequence;
+
+/// Result of looking up the keys typed so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    /// No sequence starts with these keys.
+    None,
+    /// A longer sequence may still match; carries the action to fire on timeout, if any.
+    Partial(Option<usize>),
+    /// A sequence matched and nothing longer can, fire its action now.
+    Complete(usize),
+}
+
+#[derive(Default)]
+struct Node {
+    children: Vec<(u16, usize)>,
+    action: Option<usize>,
+}
+
+/// Prefix tree over the configured leader sequences.
+pub struct LeaderTrie {
+    nodes: Vec<Node>,
+}
+
+impl LeaderTrie {
+    pub fn new(sequences: &[LeaderSequence]) -> Self {
+        let mut trie = Self {
+            nodes: vec![Node::default()],
+        };
+        for (index, sequence) in sequences.iter().enumerate() {
+            if sequence.keys.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for &code in &sequence.keys {
+                node = match trie.child(node, code) {
+                    Some(next) => next,
+                    None => {
+                        trie.nodes.push(Node::default());
+                        let next = trie.nodes.len() - 1;
+                        trie.nodes[node].children.push((code, next));
+                        next
+                    }
+                };
+            }
+            // The first definition of a sequence wins.
+            trie.nodes[node].action.get_or_insert(index);
+        }
+        trie
+    }
+
+    /// Whether `code` begins any sequence.
+    pub fn starts_with(&self, code: u16) -> bool {
+        self.child(0, code).is_some()
+    }
+
+    pub fn lookup(&self, keys: &[u16]) -> Match {
+        let mut node = 0;
+        for &code in keys {
+            match self.child(node, code) {
+                Some(next) => node = next,
+                None => return Match::None,
+            }
+        }
+        let node = &self.nodes[node];
+        match node.action {
+            Some(action) if node.children.is_empty() => Match::Complete(action),
+            action => Match::Partial(action),
+        }
+    }
+
+    fn child(&self, node: usize, code: u16) -> Option<usize> {
+        self.nodes[node]
+            .children
+            .iter()
+            .find(|&&(c, _)| c == code)
+            .map(|&(_, next)| next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(keys: &[u16]) -> LeaderSequence {
+        LeaderSequence {
+            keys: keys.to_vec(),
+            send: vec![1],
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        // g g, g d, and d alone
+        let trie = LeaderTrie::new(&[sequence(&[34, 34]), sequence(&[34, 32]), sequence(&[32])]);
+
+        assert!(trie.starts_with(34));
+        assert!(!trie.starts_with(33));
+        assert_eq!(trie.lookup(&[34]), Match::Partial(None));
+        assert_eq!(trie.lookup(&[34, 34]), Match::Complete(0));
+        assert_eq!(trie.lookup(&[34, 32]), Match::Complete(1));
+        assert_eq!(trie.lookup(&[32]), Match::Complete(2));
+        assert_eq!(trie.lookup(&[34, 33]), Match::None);
+        assert_eq!(trie.lookup(&[34, 34, 34]), Match::None);
+    }
+
+    #[test]
+    fn test_prefix_of_longer_sequence() {
+        let trie = LeaderTrie::new(&[sequence(&[34]), sequence(&[34, 34]), sequence(&[34])]);
+
+        assert_eq!(trie.lookup(&[34]), Match::Partial(Some(0)));
+        assert_eq!(trie.lookup(&[34, 34]), Match::Complete(1));
+    }
+}
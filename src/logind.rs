@@ -0,0 +1,71 @@
+use crate::CoreCommand;
+use std::sync::mpsc;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::OwnedObjectPath;
+
+const LOGIND_DEST: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const MANAGER_IFACE: &str = "org.freedesktop.login1.Manager";
+const SESSION_IFACE: &str = "org.freedesktop.login1.Session";
+
+/// Reports lock and unlock of the current session as `CoreCommand::SetLocked`.
+///
+/// Watches logind's LockedHint on the system bus and the freedesktop ScreenSaver on the
+/// session bus; either one missing is not an error.
+pub fn watch_lock(cmd_tx: mpsc::Sender<CoreCommand>) {
+    let tx = cmd_tx.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = watch_locked_hint(&tx) {
+            log::debug!("logind lock watcher unavailable: {}", e);
+        }
+    });
+    std::thread::spawn(move || {
+        if let Err(e) = watch_screensaver(&cmd_tx) {
+            log::debug!("ScreenSaver lock watcher unavailable: {}", e);
+        }
+    });
+}
+
+fn watch_locked_hint(cmd_tx: &mpsc::Sender<CoreCommand>) -> anyhow::Result<()> {
+    let conn = Connection::system()?;
+    let reply = conn.call_method(
+        Some(LOGIND_DEST),
+        LOGIND_PATH,
+        Some(MANAGER_IFACE),
+        "GetSessionByPID",
+        &(std::process::id()),
+    )?;
+    let session: OwnedObjectPath = reply.body()?;
+    let proxy = Proxy::new(&conn, LOGIND_DEST, session, SESSION_IFACE)?;
+    for change in proxy.receive_property_changed::<bool>("LockedHint") {
+        if let Ok(locked) = change.get() {
+            log::info!("Session {}", if locked { "locked" } else { "unlocked" });
+            if cmd_tx.send(CoreCommand::SetLocked(locked)).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn watch_screensaver(cmd_tx: &mpsc::Sender<CoreCommand>) -> anyhow::Result<()> {
+    let conn = Connection::session()?;
+    let proxy = Proxy::new(
+        &conn,
+        "org.freedesktop.ScreenSaver",
+        "/org/freedesktop/ScreenSaver",
+        "org.freedesktop.ScreenSaver",
+    )?;
+    for msg in proxy.receive_signal("ActiveChanged")? {
+        if let Ok(active) = msg.body::<bool>() {
+            log::info!(
+                "Screen saver {}",
+                if active { "active" } else { "inactive" }
+            );
+            if cmd_tx.send(CoreCommand::SetLocked(active)).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,59 +1,154 @@
 mod config;
+mod control;
 mod core;
+mod diagnostics;
+mod focus;
+mod leader;
+#[cfg(feature = "logind")]
+mod logind;
 #[cfg(feature = "ui")]
 mod ui;
 
 use clap::Parser;
-use config::Config;
-use core::{
-    create_uinput_device, forward_event, list_input_devices, open_device, send_key, KeyValue,
-    State, StateMachine,
-};
+use config::{Config, LockBehavior, Mode};
+use core::{create_output, list_input_devices, open_device, KeySink, State, StateMachine};
 
 #[cfg(feature = "ui")]
 use eframe::egui;
 #[cfg(feature = "ui")]
 use eframe::egui::ViewportCommand;
-use evdev::EventType;
+use evdev::raw_stream::RawDevice;
+use evdev::{EventType, InputEvent, SynchronizationCode};
 #[cfg(feature = "ui")]
 use libappindicator::AppIndicator;
+use nix::errno::Errno;
 use nix::sys::select::{select, FdSet};
 use nix::sys::time::TimeVal;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
 use std::os::fd::AsRawFd;
+use std::path::PathBuf;
 use std::sync::mpsc;
-use std::time::Duration;
+#[cfg(feature = "ui")]
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
 #[cfg(feature = "ui")]
 use ui::SpacefnApp;
 
 #[derive(Debug, Clone)]
 pub enum UiMessage {
     StateChanged(State),
-    KeyPressed(u16),
+    /// A key event read from the keyboard, or sent on the virtual one when `emitted`.
+    Key {
+        code: u16,
+        value: i32,
+        emitted: bool,
+    },
     Error(String),
 }
 
+#[derive(Debug)]
 pub enum CoreCommand {
     ReloadConfig,
+    Pause,
+    Resume,
+    /// Replaces the Fn layer mappings and numpad_numlock without touching the config file.
+    SetMappings(Vec<[u32; 3]>, bool),
+    /// Swaps in the config at the path, keeping the current one for `RevertConfig`.
+    LoadConfig(PathBuf, control::Reply),
+    /// Goes back to the config replaced by the last `LoadConfig`.
+    RevertConfig(control::Reply),
+    /// Switches the Fn layer mapping of a source key on or off, until restart.
+    SetMappingEnabled(u16, bool, control::Reply),
+    /// Releases every key spacefn holds and returns to Idle, for stuck keys.
+    Reset,
+    /// Sent by the screen lock watcher.
+    SetLocked(bool),
+    /// Sent by the focus watcher while an app in pause_for_apps is focused.
+    SetFocusPaused(bool),
     Stop,
 }
 
-const KEY_SPACE: u16 = 57;
-const DECIDE_TIMEOUT_MS: u64 = 200;
+impl CoreCommand {
+    /// The copy of this command for device groups, if it concerns them too.
+    fn for_groups(&self) -> Option<CoreCommand> {
+        match self {
+            CoreCommand::ReloadConfig => Some(CoreCommand::ReloadConfig),
+            CoreCommand::Pause => Some(CoreCommand::Pause),
+            CoreCommand::Resume => Some(CoreCommand::Resume),
+            CoreCommand::Reset => Some(CoreCommand::Reset),
+            CoreCommand::SetLocked(locked) => Some(CoreCommand::SetLocked(*locked)),
+            CoreCommand::SetFocusPaused(paused) => Some(CoreCommand::SetFocusPaused(*paused)),
+            CoreCommand::Stop => Some(CoreCommand::Stop),
+            // Mappings and configs from the window or socket are for the main keyboard.
+            CoreCommand::SetMappings(..)
+            | CoreCommand::LoadConfig(..)
+            | CoreCommand::RevertConfig(_)
+            | CoreCommand::SetMappingEnabled(..) => None,
+        }
+    }
+}
+
+const COMMAND_POLL_MS: u64 = 100;
 
 #[derive(Parser, Debug)]
 #[command(version, about = "SpaceFN - SpaceFN keyboard modifier")]
 struct Args {
     #[arg(long, help = "Run in CLI mode without GUI")]
     cli: bool,
+    #[arg(long, help = "Write a diagnostics report for bug reports and exit")]
+    diagnostics: bool,
+    #[arg(long, help = "Grab keyboards even if they look like virtual devices")]
+    force: bool,
 }
 
-#[cfg(feature = "ui")]
+#[cfg(any(feature = "ui", test))]
 #[derive(Clone, Debug)]
 enum TrayCommand {
     ShowWindow,
+    ReloadConfig,
+    TogglePause,
+    Reset,
     Quit,
 }
 
+#[cfg(any(feature = "ui", test))]
+/// Window-side state that tray commands act on.
+#[derive(Debug, Default, PartialEq)]
+struct TrayState {
+    show_window: bool,
+    should_exit: bool,
+    paused: bool,
+}
+
+#[cfg(any(feature = "ui", test))]
+/// Applies a tray command to `state` and returns what to forward to the core.
+fn handle_tray_command(cmd: TrayCommand, state: &mut TrayState) -> Vec<CoreCommand> {
+    match cmd {
+        TrayCommand::ShowWindow => {
+            state.show_window = true;
+            Vec::new()
+        }
+        TrayCommand::ReloadConfig => vec![CoreCommand::ReloadConfig],
+        TrayCommand::Reset => vec![CoreCommand::Reset],
+        TrayCommand::TogglePause => {
+            state.paused = !state.paused;
+            vec![if state.paused {
+                CoreCommand::Pause
+            } else {
+                CoreCommand::Resume
+            }]
+        }
+        TrayCommand::Quit => {
+            state.should_exit = true;
+            vec![CoreCommand::Stop]
+        }
+    }
+}
+
 fn init_logging() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
         .format_timestamp_millis()
@@ -61,38 +156,196 @@ fn init_logging() {
     log::info!("spacefn-rs started");
 }
 
+/// Watches for the session locking when lock_behavior asks for it.
+fn spawn_lock_watcher(config: &Config, cmd_tx: &mpsc::Sender<CoreCommand>) {
+    if config.lock_behavior == LockBehavior::Off {
+        return;
+    }
+    #[cfg(feature = "logind")]
+    logind::watch_lock(cmd_tx.clone());
+    #[cfg(not(feature = "logind"))]
+    {
+        let _ = cmd_tx;
+        log::warn!("lock_behavior needs the logind feature, the session lock is not watched");
+    }
+}
+
+/// Reloads the config on SIGHUP, like other daemons.
+fn spawn_signal_thread(cmd_tx: mpsc::Sender<CoreCommand>) {
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            log::warn!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    std::thread::spawn(move || forward_signals(signals.forever(), &cmd_tx));
+}
+
+fn forward_signals(signals: impl IntoIterator<Item = i32>, cmd_tx: &mpsc::Sender<CoreCommand>) {
+    for signal in signals {
+        if signal == SIGHUP {
+            log::info!("SIGHUP received, reloading config");
+            if cmd_tx.send(CoreCommand::ReloadConfig).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Starts a state machine for each group in `config`. Returns the receiver for the main
+/// state machine, fed from `cmd_rx` with the group-wide commands copied to every group.
+fn start_groups(
+    config: &Config,
+    cmd_rx: mpsc::Receiver<CoreCommand>,
+) -> mpsc::Receiver<CoreCommand> {
+    let mut group_txs = Vec::new();
+    for group in &config.groups {
+        if group.config.keyboard.is_empty() {
+            continue;
+        }
+        let (group_tx, group_rx) = mpsc::channel();
+        // Only the main keyboard is shown in the window.
+        let (state_tx, _) = mpsc::channel();
+        let name = group.name.clone();
+        let group_config = group.config.clone();
+        std::thread::spawn(move || {
+            let device_path = group_config.keyboard.clone();
+            log::info!("Starting group {:?} on {}", name, device_path);
+            if let Err(e) =
+                run_state_machine(&device_path, group_config, state_tx, group_rx, Some(&name))
+            {
+                log::error!("Group {:?} error: {}", name, e);
+            }
+        });
+        group_txs.push(group_tx);
+    }
+    if group_txs.is_empty() {
+        return cmd_rx;
+    }
+    let (main_tx, main_rx) = mpsc::channel();
+    std::thread::spawn(move || fan_out(cmd_rx, &main_tx, &group_txs));
+    main_rx
+}
+
+fn fan_out(
+    cmd_rx: mpsc::Receiver<CoreCommand>,
+    main_tx: &mpsc::Sender<CoreCommand>,
+    group_txs: &[mpsc::Sender<CoreCommand>],
+) {
+    for cmd in cmd_rx {
+        for tx in group_txs {
+            if let Some(copy) = cmd.for_groups() {
+                // A group whose keyboard failed has stopped, the others go on.
+                let _ = tx.send(copy);
+            }
+        }
+        if main_tx.send(cmd).is_err() {
+            break;
+        }
+    }
+}
+
+/// Reads the config again, or the part of it for `group`.
+fn reload_config(group: Option<&str>) -> Option<Config> {
+    let config = Config::load().ok()?;
+    let Some(name) = group else {
+        return Some(config);
+    };
+    let group_config = config.group(name).cloned();
+    if group_config.is_none() {
+        log::warn!(
+            "Group {:?} is no longer in the config, keeping it as is",
+            name
+        );
+    }
+    group_config
+}
+
 fn run_cli_mode(device_path: &str, config: Config) {
     log::info!("Running in CLI mode");
     let (cmd_tx, cmd_rx) = mpsc::channel();
     let (state_tx, _state_rx) = mpsc::channel();
     drop(_state_rx);
-    if let Err(e) = run_state_machine(device_path, config, state_tx, cmd_rx) {
+    spawn_lock_watcher(&config, &cmd_tx);
+    control::spawn_control_socket(cmd_tx.clone());
+    focus::spawn_focus_watcher(&config, cmd_tx.clone());
+    spawn_signal_thread(cmd_tx);
+    let cmd_rx = start_groups(&config, cmd_rx);
+    if let Err(e) = run_state_machine(device_path, config, state_tx, cmd_rx, None) {
         log::error!("Core error: {}", e);
     }
 }
 
 #[cfg(feature = "ui")]
 fn run_ui_mode(device_path: String, config: Config) {
+    let (core_tx, core_rx) = mpsc::channel::<UiMessage>();
     let (state_tx, state_rx) = mpsc::channel();
     let (cmd_tx, cmd_rx) = mpsc::channel();
     let (tray_tx, tray_rx) = mpsc::channel();
+    let (tray_state_tx, tray_state_rx) = mpsc::channel();
 
-    spawn_tray_thread(tray_tx);
+    let tray_available = spawn_tray_thread(tray_tx, tray_state_rx);
+
+    // Fan core messages out to the window and the tray tooltip.
+    std::thread::spawn(move || {
+        for msg in core_rx {
+            if let UiMessage::StateChanged(state) = msg {
+                let _ = tray_state_tx.send(state);
+            }
+            if state_tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
 
     std::thread::sleep(Duration::from_millis(100));
 
+    spawn_lock_watcher(&config, &cmd_tx);
+    control::spawn_control_socket(cmd_tx.clone());
+    focus::spawn_focus_watcher(&config, cmd_tx.clone());
+    spawn_signal_thread(cmd_tx.clone());
+
+    let cmd_rx = start_groups(&config, cmd_rx);
     let device_path_clone = device_path.clone();
     let config_clone = config.clone();
     let core_handle = std::thread::spawn(move || {
-        if let Err(e) = run_state_machine(&device_path_clone, config_clone, state_tx, cmd_rx) {
+        if let Err(e) = run_state_machine(&device_path_clone, config_clone, core_tx, cmd_rx, None) {
             log::error!("Core error: {}", e);
         }
     });
 
-    run_ui(state_rx, cmd_tx, tray_rx);
+    run_ui(
+        state_rx,
+        cmd_tx,
+        tray_rx,
+        tray_available,
+        config.keep_running_hidden,
+    );
     let _ = core_handle.join();
 }
 
+/// Refuses a keyboard that looks like a virtual device unless `force` is set. A device that
+/// cannot be opened is left for the usual open to report.
+fn check_not_virtual(device_path: &str, force: bool) -> anyhow::Result<()> {
+    let Ok(device) = open_device(device_path) else {
+        return Ok(());
+    };
+    let name = device.name().unwrap_or_default();
+    let Some(reason) = core::virtual_device_reason(device.input_id().bus_type(), name) else {
+        return Ok(());
+    };
+    if force {
+        log::warn!("{} looks like a virtual device, {}", device_path, reason);
+        return Ok(());
+    }
+    anyhow::bail!(
+        "{} looks like a virtual device, {}. Pass --force to grab it anyway",
+        device_path,
+        reason
+    )
+}
+
 fn check_device_permissions(device_path: &str) -> anyhow::Result<()> {
     match core::check_permissions(device_path) {
         Ok(()) => {
@@ -109,226 +362,429 @@ fn check_device_permissions(device_path: &str) -> anyhow::Result<()> {
     }
 }
 
-fn wait_for_event(fd: std::os::unix::io::RawFd, timeout_ms: u64) -> bool {
+#[derive(Debug, PartialEq)]
+enum WaitResult {
+    Ready,
+    TimedOut,
+    /// A signal arrived before the device became readable.
+    Interrupted,
+}
+
+fn wait_for_event(fd: std::os::unix::io::RawFd, timeout_ms: u64) -> anyhow::Result<WaitResult> {
     let mut readfds = FdSet::new();
     readfds.insert(fd);
     let mut timeout = TimeVal::new(0, (timeout_ms * 1000) as i64);
-    match select(None, &mut readfds, None, None, Some(&mut timeout)) {
-        Ok(n) => n > 0,
-        Err(_) => false,
+    classify_select(select(None, &mut readfds, None, None, Some(&mut timeout)))
+}
+
+fn classify_select(result: nix::Result<i32>) -> anyhow::Result<WaitResult> {
+    match result {
+        Ok(0) => Ok(WaitResult::TimedOut),
+        Ok(_) => Ok(WaitResult::Ready),
+        Err(Errno::EINTR) => Ok(WaitResult::Interrupted),
+        Err(e) => Err(anyhow::anyhow!("Waiting for input failed: {}", e)),
+    }
+}
+
+/// Passes output through to `inner`, noting the key events for the UI.
+struct EmitLog<'a, S: KeySink> {
+    inner: &'a mut S,
+    keys: Vec<(u16, i32)>,
+}
+
+impl<'a, S: KeySink> EmitLog<'a, S> {
+    fn new(inner: &'a mut S) -> Self {
+        Self {
+            inner,
+            keys: Vec::new(),
+        }
+    }
+
+    fn report(self, state_tx: &mpsc::Sender<UiMessage>) {
+        for (code, value) in self.keys {
+            let _ = state_tx.send(UiMessage::Key {
+                code,
+                value,
+                emitted: true,
+            });
+        }
+    }
+}
+
+impl<S: KeySink> KeySink for EmitLog<'_, S> {
+    fn send_key(&mut self, code: u16, value: i32) -> anyhow::Result<()> {
+        self.keys.push((code, value));
+        self.inner.send_key(code, value)
+    }
+
+    fn forward_event(&mut self, event: &evdev::InputEvent) -> anyhow::Result<()> {
+        self.inner.forward_event(event)
+    }
+
+    fn exec(&mut self, command: &str) -> anyhow::Result<()> {
+        self.inner.exec(command)
     }
 }
 
+/// Warns when the output device lacks keys we asked for, and tells the window when it has none.
+fn check_registration(
+    output: &mut core::Output,
+    device: &RawDevice,
+    targets: &[u16],
+    state_tx: &mpsc::Sender<UiMessage>,
+) {
+    let registered = match output.registered_keys() {
+        Ok(registered) => registered,
+        Err(e) => {
+            log::debug!("Could not read back the output device's keys: {}", e);
+            return;
+        }
+    };
+    let requested = core::requested_keys(device, targets);
+    log::info!(
+        "Output device has {} of {} requested keys",
+        registered.len(),
+        requested.len()
+    );
+    for warning in core::registration_warnings(&requested, &registered, targets) {
+        log::warn!("{}", warning);
+        if registered.is_empty() {
+            let _ = state_tx.send(UiMessage::Error(warning));
+        }
+    }
+}
+
+/// Creates the output for `device` with every key `config` can send, warning about
+/// targets the output cannot take.
+fn open_output(
+    device: &RawDevice,
+    device_path: &str,
+    config: &Config,
+    state_tx: &mpsc::Sender<UiMessage>,
+) -> anyhow::Result<core::Output> {
+    let targets = core::output_codes(config);
+    let mut output = create_output(device, device_path, &targets, &config.output_device)?;
+    check_registration(&mut output, device, &targets, state_tx);
+    Ok(output)
+}
+
+/// Opens and, unless paused, grabs the keyboard `config` names if it is not the one in use.
+fn open_switch_target(
+    config: &Config,
+    current: &str,
+    paused: bool,
+) -> anyhow::Result<Option<RawDevice>> {
+    if config.keyboard.is_empty()
+        || core::resolve_device_path(&config.keyboard) == core::resolve_device_path(current)
+    {
+        return Ok(None);
+    }
+    let mut device = open_device(&config.keyboard)?;
+    if !paused {
+        device.grab()?;
+    }
+    log::info!("Switching to {}", config.keyboard);
+    Ok(Some(device))
+}
+
+/// Remaps `device_path` until stopped. `group` names the device group being run, if any,
+/// whose part of the config is used on reload.
 fn run_state_machine(
     device_path: &str,
     config: Config,
     state_tx: mpsc::Sender<UiMessage>,
     cmd_rx: mpsc::Receiver<CoreCommand>,
+    group: Option<&str>,
 ) -> anyhow::Result<()> {
     let mut device = open_device(device_path)?;
-    let mut uinput = create_uinput_device(&device)?;
+    // Switching configs later never changes the mode.
+    let mode = config.mode;
+    if !mode.grabs() {
+        return run_monitor(device, device_path, config.lock_behavior, state_tx, cmd_rx);
+    }
+    if !mode.remaps() {
+        log::info!("Passthrough mode, forwarding every event unchanged");
+    }
+    let mut device_path = device_path.to_string();
+    let mut uinput = open_output(&device, &device_path, &config, &state_tx)?;
+    // What the output was built for, to build it again when a new config needs other keys.
+    let mut output_for = (core::output_codes(&config), config.output_device.clone());
     std::thread::sleep(Duration::from_millis(200));
     device.grab()?;
-    let mut state = State::Idle;
-    let mut buffer: Vec<u16> = Vec::new();
-    let mut current_config = config;
-    let _ = state_tx.send(UiMessage::StateChanged(state));
+    let mut sm = StateMachine::new(config);
+    sm.set_numlock(core::numlock_led(&device));
+    sm.start_grace(Instant::now());
+    let mut dropped = false;
+    let (mut user_paused, mut locked, mut paused) = (false, false, false);
+    let mut focus_paused = false;
+    let mut last_reconcile = Instant::now();
+    let _ = state_tx.send(UiMessage::StateChanged(sm.state()));
 
     loop {
-        match state {
-            State::Idle => {
-                state = run_idle_state(
-                    &mut device,
-                    &mut uinput,
-                    &current_config,
-                    &state_tx,
-                    &cmd_rx,
-                )?
-            }
-            State::Decide => {
-                state = run_decide_state(
-                    &mut device,
-                    &mut uinput,
-                    &mut buffer,
-                    &current_config,
-                    &state_tx,
-                    &cmd_rx,
-                )?
+        // Commands are only read between waits, so never wait longer than a poll.
+        let poll = Duration::from_millis(COMMAND_POLL_MS);
+        let timeout = sm
+            .deadline()
+            .map_or(poll, |d| d.saturating_duration_since(Instant::now()))
+            .min(poll);
+        match wait_for_event(device.as_raw_fd(), timeout.as_millis() as u64)? {
+            // Nothing to read, go on to the commands and wait again.
+            WaitResult::Interrupted => {}
+            WaitResult::TimedOut => {
+                if sm.deadline().is_some() {
+                    let prev = sm.state();
+                    let private = locked && sm.config.lock_behavior == LockBehavior::PrivacyOnly;
+                    let mut log = EmitLog::new(&mut uinput);
+                    let state = sm.poll_timeout(&mut log, Instant::now())?;
+                    if !private {
+                        log.report(&state_tx);
+                    }
+                    if state != prev {
+                        let _ = state_tx.send(UiMessage::StateChanged(state));
+                    }
+                }
+                let interval = sm.config.reconcile_interval_ms;
+                if interval > 0
+                    && !paused
+                    && last_reconcile.elapsed().as_millis() as u64 >= interval
+                {
+                    // Only while no events are pending, so the kernel state is not ahead of us.
+                    last_reconcile = Instant::now();
+                    let held: Vec<u16> = device.get_key_state()?.iter().map(|k| k.code()).collect();
+                    let prev = sm.state();
+                    let state = sm.reconcile(&mut uinput, &held, Instant::now())?;
+                    if state != prev {
+                        let _ = state_tx.send(UiMessage::StateChanged(state));
+                    }
+                }
             }
-            State::Shift => {
-                state = run_shift_state(
-                    &mut device,
-                    &mut uinput,
-                    &mut buffer,
-                    &current_config,
-                    &state_tx,
-                    &cmd_rx,
-                )?
+            WaitResult::Ready => {
+                let events: Vec<_> = device.fetch_events()?.collect();
+                let private = locked && sm.config.lock_behavior == LockBehavior::PrivacyOnly;
+                for event in events {
+                    if paused {
+                        continue;
+                    }
+                    if event.event_type() == EventType::SYNCHRONIZATION {
+                        if event.code() == SynchronizationCode::SYN_DROPPED.0 {
+                            log::warn!("Kernel dropped input events, resynchronizing");
+                            dropped = true;
+                            continue;
+                        }
+                        if dropped && event.code() == SynchronizationCode::SYN_REPORT.0 {
+                            // Everything up to this report is incomplete, read the real state instead.
+                            dropped = false;
+                            let held: Vec<u16> =
+                                device.get_key_state()?.iter().map(|k| k.code()).collect();
+                            let prev = sm.state();
+                            let state = sm.resync(&mut uinput, &held, Instant::now())?;
+                            if state != prev {
+                                let _ = state_tx.send(UiMessage::StateChanged(state));
+                            }
+                            continue;
+                        }
+                    }
+                    if dropped {
+                        continue;
+                    }
+                    if event.event_type() != EventType::KEY {
+                        uinput.forward_event(&event)?;
+                        continue;
+                    }
+                    if !private {
+                        let _ = state_tx.send(UiMessage::Key {
+                            code: event.code(),
+                            value: event.value(),
+                            emitted: false,
+                        });
+                    }
+                    let prev = sm.state();
+                    let mut log = EmitLog::new(&mut uinput);
+                    let state = dispatch_key(&mut sm, mode, &mut log, &event, Instant::now())?;
+                    if !private {
+                        log.report(&state_tx);
+                    }
+                    if state != prev {
+                        let _ = state_tx.send(UiMessage::StateChanged(state));
+                    }
+                }
             }
         }
+        let (mut reloaded, mut switched) = (false, false);
         while let Ok(cmd) = cmd_rx.try_recv() {
             match cmd {
+                // Several sources asking at once, e.g. SIGHUP and the UI, reload only once.
+                CoreCommand::ReloadConfig if reloaded => {}
                 CoreCommand::ReloadConfig => {
-                    if let Ok(new_config) = Config::load() {
-                        current_config = new_config;
+                    reloaded = true;
+                    if let Some(new_config) = reload_config(group) {
+                        sm.reload_config(&mut uinput, new_config)?;
                     }
                 }
+                CoreCommand::SetMappings(keys_map, numpad_numlock) => {
+                    sm.set_mappings(&mut uinput, keys_map, numpad_numlock)?
+                }
+                CoreCommand::LoadConfig(path, reply) => {
+                    let result = Config::load_from(&path).and_then(|config| {
+                        if let Some(new_device) = open_switch_target(&config, &device_path, paused)?
+                        {
+                            sm.release_all(&mut uinput)?;
+                            device = new_device;
+                            device_path = config.keyboard.clone();
+                            switched = true;
+                        }
+                        log::info!("Loaded config from {:?}", path);
+                        sm.load_config(&mut uinput, config)
+                    });
+                    let _ = reply.send(result.map(|()| String::new()).map_err(|e| e.to_string()));
+                }
+                CoreCommand::RevertConfig(reply) => {
+                    let result = (|| {
+                        let Some(previous) = sm.previous_config() else {
+                            anyhow::bail!("No earlier config to revert to");
+                        };
+                        let keyboard = previous.keyboard.clone();
+                        if let Some(new_device) =
+                            open_switch_target(previous, &device_path, paused)?
+                        {
+                            sm.release_all(&mut uinput)?;
+                            device = new_device;
+                            device_path = keyboard;
+                            switched = true;
+                        }
+                        sm.revert_config(&mut uinput)
+                    })();
+                    let _ = reply.send(result.map(|()| String::new()).map_err(|e| e.to_string()));
+                }
+                CoreCommand::SetMappingEnabled(source, enabled, reply) => {
+                    let state = if enabled { "enabled" } else { "disabled" };
+                    let result = sm
+                        .set_mapping_enabled(&mut uinput, source, enabled)
+                        .map(|()| format!("{} {}", source, state));
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                }
+                CoreCommand::Reset => {
+                    log::info!("Resetting held keys");
+                    sm.reset(&mut uinput)?;
+                    let _ = state_tx.send(UiMessage::StateChanged(sm.state()));
+                }
+                CoreCommand::Pause => user_paused = true,
+                CoreCommand::Resume => user_paused = false,
+                CoreCommand::SetLocked(value) => locked = value,
+                CoreCommand::SetFocusPaused(value) => focus_paused = value,
                 CoreCommand::Stop => return Ok(()),
             }
         }
-    }
-}
-
-fn run_idle_state(
-    device: &mut evdev::Device,
-    uinput: &mut evdev::uinput::VirtualDevice,
-    _config: &Config,
-    state_tx: &mpsc::Sender<UiMessage>,
-    _cmd_rx: &mpsc::Receiver<CoreCommand>,
-) -> anyhow::Result<State> {
-    loop {
-        for event in device.fetch_events()? {
-            if event.event_type() != EventType::KEY {
-                forward_event(uinput, &event)?;
-                continue;
+        // A new keyboard or config, also one deferred until keys were released, may need
+        // keys the output lacks; the old output is kept if a new one cannot be made.
+        let wanted = (
+            core::output_codes(&sm.config),
+            sm.config.output_device.clone(),
+        );
+        if switched || wanted != output_for {
+            sm.release_all(&mut uinput)?;
+            match open_output(&device, &device_path, &sm.config, &state_tx) {
+                Ok(output) => uinput = output,
+                Err(e) => {
+                    log::error!("Failed to rebuild the output device: {}", e);
+                    let _ = state_tx.send(UiMessage::Error(e.to_string()));
+                }
             }
-            let (code, value) = (event.code(), KeyValue::from(event.value()));
-            let _ = state_tx.send(UiMessage::KeyPressed(code));
-            if code == KEY_SPACE && value == KeyValue::Press {
-                let _ = state_tx.send(UiMessage::StateChanged(State::Decide));
-                return Ok(State::Decide);
+            output_for = wanted;
+        }
+        // A manual pause outlives the lock and focus, neither ever resumes it.
+        let want_paused = user_paused
+            || focus_paused
+            || (locked && sm.config.lock_behavior == LockBehavior::Pause);
+        if want_paused != paused {
+            paused = want_paused;
+            if paused {
+                log::info!("Pausing remapping");
+                sm.flush(&mut uinput)?;
+                let _ = state_tx.send(UiMessage::StateChanged(sm.state()));
+                device.ungrab()?;
+            } else {
+                log::info!("Resuming remapping");
+                device.grab()?;
+                // NumLock may have been toggled while the keyboard was not ours.
+                sm.set_numlock(core::numlock_led(&device));
+                sm.start_grace(Instant::now());
             }
-            send_key(uinput, code, event.value())?;
         }
     }
 }
 
-fn run_decide_state(
-    device: &mut evdev::Device,
-    uinput: &mut evdev::uinput::VirtualDevice,
-    buffer: &mut Vec<u16>,
-    config: &Config,
-    state_tx: &mpsc::Sender<UiMessage>,
-    _cmd_rx: &mpsc::Receiver<CoreCommand>,
+/// Sends a key read from the keyboard on, through `sm` if `mode` remaps, else unchanged.
+fn dispatch_key<S: KeySink>(
+    sm: &mut StateMachine,
+    mode: Mode,
+    sink: &mut S,
+    event: &InputEvent,
+    now: Instant,
 ) -> anyhow::Result<State> {
-    buffer.clear();
-    let start = std::time::Instant::now();
-    let timeout = Duration::from_millis(DECIDE_TIMEOUT_MS);
-    let fd = device.as_raw_fd();
-    loop {
-        let elapsed = start.elapsed();
-        if elapsed >= timeout {
-            for &code in buffer.iter() {
-                send_mapped_key(uinput, code, KeyValue::Press, config)?;
-            }
-            let _ = state_tx.send(UiMessage::StateChanged(State::Shift));
-            return Ok(State::Shift);
-        }
-        let remaining = (timeout - elapsed).as_millis() as u64;
-        if !wait_for_event(fd, remaining) {
-            continue;
-        }
-        for event in device.fetch_events()? {
-            if event.event_type() != EventType::KEY {
-                forward_event(uinput, &event)?;
-                continue;
-            }
-            let (code, value) = (event.code(), KeyValue::from(event.value()));
-            let _ = state_tx.send(UiMessage::KeyPressed(code));
-            if code == KEY_SPACE && value == KeyValue::Release {
-                send_key(uinput, KEY_SPACE, 1)?;
-                send_key(uinput, KEY_SPACE, 0)?;
-                for &code in buffer.iter() {
-                    send_key(uinput, code, 1)?;
-                }
-                let _ = state_tx.send(UiMessage::StateChanged(State::Idle));
-                return Ok(State::Idle);
-            }
-            if value == KeyValue::Press {
-                if !buffer.contains(&code) {
-                    buffer.push(code);
-                }
-                continue;
-            }
-            if value == KeyValue::Release && !buffer.contains(&code) {
-                send_key(uinput, code, event.value())?;
-                continue;
-            }
-            if value == KeyValue::Release && buffer.contains(&code) {
-                if let Some(pos) = buffer.iter().position(|&x| x == code) {
-                    buffer.remove(pos);
-                }
-                send_mapped_key(uinput, code, KeyValue::Press, config)?;
-                send_mapped_key(uinput, code, KeyValue::Release, config)?;
-                let _ = state_tx.send(UiMessage::StateChanged(State::Shift));
-                return Ok(State::Shift);
-            }
-        }
+    if !mode.remaps() {
+        sink.forward_event(event)?;
+        return Ok(sm.state());
     }
+    sm.handle_key(sink, event.code(), event.value(), now)
 }
 
-fn run_shift_state(
-    device: &mut evdev::Device,
-    uinput: &mut evdev::uinput::VirtualDevice,
-    buffer: &mut Vec<u16>,
-    config: &Config,
-    state_tx: &mpsc::Sender<UiMessage>,
-    _cmd_rx: &mpsc::Receiver<CoreCommand>,
-) -> anyhow::Result<State> {
+/// Tells the window about the keys among `events`, unless `private`.
+fn report_monitored(events: &[InputEvent], private: bool, state_tx: &mpsc::Sender<UiMessage>) {
+    if private {
+        return;
+    }
+    for event in events.iter().filter(|e| e.event_type() == EventType::KEY) {
+        log::trace!("Key {} value {}", event.code(), event.value());
+        let _ = state_tx.send(UiMessage::Key {
+            code: event.code(),
+            value: event.value(),
+            emitted: false,
+        });
+    }
+}
+
+/// Reports the keys of `device` without grabbing or remapping it, see `Mode::Monitor`.
+fn run_monitor(
+    mut device: RawDevice,
+    device_path: &str,
+    lock_behavior: LockBehavior,
+    state_tx: mpsc::Sender<UiMessage>,
+    cmd_rx: mpsc::Receiver<CoreCommand>,
+) -> anyhow::Result<()> {
+    log::info!("Monitoring {} without remapping", device_path);
+    let _ = state_tx.send(UiMessage::StateChanged(State::Idle));
+    let mut locked = false;
     loop {
-        for event in device.fetch_events()? {
-            if event.event_type() != EventType::KEY {
-                forward_event(uinput, &event)?;
-                continue;
-            }
-            let (code, value) = (event.code(), KeyValue::from(event.value()));
-            let _ = state_tx.send(UiMessage::KeyPressed(code));
-            if code == KEY_SPACE && value == KeyValue::Release {
-                for &code in buffer.iter() {
-                    send_mapped_key(uinput, code, KeyValue::Release, config)?;
-                }
-                buffer.clear();
-                let _ = state_tx.send(UiMessage::StateChanged(State::Idle));
-                return Ok(State::Idle);
-            }
-            if code == KEY_SPACE {
-                continue;
-            }
-            let mapped = send_mapped_key(uinput, code, value, config)?;
-            if mapped {
-                if value == KeyValue::Press {
-                    if !buffer.contains(&code) {
-                        buffer.push(code);
-                    }
-                } else if value == KeyValue::Release {
-                    if let Some(pos) = buffer.iter().position(|&x| x == code) {
-                        buffer.remove(pos);
-                    }
+        if wait_for_event(device.as_raw_fd(), COMMAND_POLL_MS)? == WaitResult::Ready {
+            let events: Vec<_> = device.fetch_events()?.collect();
+            // Nothing is remapped to pause, so either lock behavior keeps keys private.
+            let private = locked && lock_behavior != LockBehavior::Off;
+            report_monitored(&events, private, &state_tx);
+        }
+        while let Ok(cmd) = cmd_rx.try_recv() {
+            match cmd {
+                CoreCommand::LoadConfig(_, reply)
+                | CoreCommand::RevertConfig(reply)
+                | CoreCommand::SetMappingEnabled(_, _, reply) => {
+                    let _ = reply.send(Err("Not available in monitor mode".to_string()));
                 }
+                CoreCommand::SetLocked(value) => locked = value,
+                CoreCommand::Stop => return Ok(()),
+                _ => {}
             }
         }
     }
 }
 
-fn send_mapped_key(
-    uinput: &mut evdev::uinput::VirtualDevice,
-    code: u16,
-    value: KeyValue,
-    config: &Config,
-) -> anyhow::Result<bool> {
-    let sm = StateMachine::new(config.clone());
-    let (mapped_code, ext_code) = sm.map_key(code);
-    let actual_code = if mapped_code != 0 { mapped_code } else { code };
-    if let Some(ext) = ext_code {
-        send_key(uinput, ext, value as i32)?;
-    }
-    send_key(uinput, actual_code, value as i32)?;
-    Ok(mapped_code != 0 && mapped_code != code)
-}
-
 #[cfg(feature = "ui")]
-fn spawn_tray_thread(tray_tx: mpsc::Sender<TrayCommand>) {
+fn spawn_tray_thread(
+    tray_tx: mpsc::Sender<TrayCommand>,
+    tray_state_rx: mpsc::Receiver<State>,
+) -> Arc<AtomicBool> {
     use gtk::prelude::*;
+    let tray_available = Arc::new(AtomicBool::new(false));
+    let available = tray_available.clone();
     std::thread::spawn(move || {
         if gtk::init().is_err() {
             log::warn!("Failed to initialize GTK with default settings");
@@ -351,6 +807,7 @@ fn spawn_tray_thread(tray_tx: mpsc::Sender<TrayCommand>) {
         let mut indicator = AppIndicator::new("spacefn-rs", "spacefn-icon");
         indicator.set_status(libappindicator::AppIndicatorStatus::Active);
         indicator.set_icon_full(temp_icon_path.to_str().unwrap_or(""), "spacefn-icon");
+        indicator.set_title("SpaceFN");
         log::info!("Tray icon set from: {:?}", temp_icon_path);
 
         let mut menu = gtk::Menu::new();
@@ -363,10 +820,32 @@ fn spawn_tray_thread(tray_tx: mpsc::Sender<TrayCommand>) {
         });
         menu.append(&show_item);
 
+        let reload_item = gtk::MenuItem::with_label("重新加载配置");
+        let tx_reload = tray_tx.clone();
+        reload_item.connect_activate(move |_| {
+            let _ = tx_reload.send(TrayCommand::ReloadConfig);
+        });
+        menu.append(&reload_item);
+
+        let pause_item = gtk::MenuItem::with_label("暂停/恢复");
+        let tx_pause = tray_tx.clone();
+        pause_item.connect_activate(move |_| {
+            let _ = tx_pause.send(TrayCommand::TogglePause);
+        });
+        menu.append(&pause_item);
+
+        let reset_item = gtk::MenuItem::with_label("重置按键");
+        let tx_reset = tray_tx.clone();
+        reset_item.connect_activate(move |_| {
+            let _ = tx_reset.send(TrayCommand::Reset);
+        });
+        menu.append(&reset_item);
+
         let quit_item = gtk::MenuItem::with_label("退出");
+        let tx_quit = tray_tx.clone();
         quit_item.connect_activate(move |_| {
             log::info!("Quit clicked");
-            std::process::exit(0);
+            let _ = tx_quit.send(TrayCommand::Quit);
         });
         menu.append(&quit_item);
 
@@ -374,9 +853,18 @@ fn spawn_tray_thread(tray_tx: mpsc::Sender<TrayCommand>) {
         indicator.set_menu(&mut menu);
 
         log::info!("Tray icon created successfully");
+        available.store(true, Ordering::SeqCst);
+
+        gtk::glib::timeout_add_local(Duration::from_millis(100), move || {
+            while let Ok(state) = tray_state_rx.try_recv() {
+                indicator.set_title(&format!("SpaceFN — {}", ui::state_label(state)));
+            }
+            gtk::glib::Continue(true)
+        });
 
         gtk::main();
     });
+    tray_available
 }
 
 #[cfg(feature = "ui")]
@@ -384,11 +872,15 @@ fn run_ui(
     state_rx: mpsc::Receiver<UiMessage>,
     cmd_tx: mpsc::Sender<CoreCommand>,
     tray_rx: mpsc::Receiver<TrayCommand>,
+    tray_available: Arc<AtomicBool>,
+    keep_running_hidden: bool,
 ) {
+    let ui_settings = config::UiSettings::load();
+    let [min_width, min_height] = ui_settings.min_window_size;
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
-            .with_inner_size([700.0, 600.0])
-            .with_min_inner_size([500.0, 400.0]),
+            .with_inner_size([min_width.max(700.0), min_height.max(600.0)])
+            .with_min_inner_size([min_width, min_height]),
         ..Default::default()
     };
 
@@ -399,14 +891,22 @@ fn run_ui(
     eframe::run_native(
         "SpaceFN",
         options,
-        Box::new(move |_cc| {
+        Box::new(move |cc| {
+            cc.egui_ctx.set_zoom_factor(ui_settings.scale);
             let mut app = SpacefnApp::new();
+            app.selected_device = app.devices.iter().position(|d| {
+                d.path == ui_settings.last_device
+                    || d.stable_path.as_ref() == Some(&ui_settings.last_device)
+            });
+            app.ui_settings = ui_settings;
             app.reload_config();
             Box::new(SpacefnAppWrapper {
                 app,
                 state_rx,
-                _cmd_tx: cmd_tx,
+                cmd_tx,
                 tray_rx,
+                tray_available,
+                keep_running_hidden,
                 should_exit: false,
             })
         }),
@@ -418,8 +918,10 @@ fn run_ui(
 struct SpacefnAppWrapper {
     app: SpacefnApp,
     state_rx: std::sync::Mutex<mpsc::Receiver<UiMessage>>,
-    _cmd_tx: std::sync::Mutex<mpsc::Sender<CoreCommand>>,
+    cmd_tx: std::sync::Mutex<mpsc::Sender<CoreCommand>>,
     tray_rx: std::sync::Mutex<mpsc::Receiver<TrayCommand>>,
+    tray_available: Arc<AtomicBool>,
+    keep_running_hidden: bool,
     should_exit: bool,
 }
 
@@ -432,38 +934,67 @@ impl eframe::App for SpacefnAppWrapper {
         }
 
         if let Ok(tray_rx) = self.tray_rx.lock() {
+            let mut state = TrayState {
+                show_window: false,
+                should_exit: self.should_exit,
+                paused: self.app.paused,
+            };
             while let Ok(cmd) = tray_rx.try_recv() {
-                match cmd {
-                    TrayCommand::ShowWindow => {
-                        log::info!("Processing ShowWindow command");
-                        ctx.send_viewport_cmd(ViewportCommand::Visible(true));
-                        ctx.send_viewport_cmd(ViewportCommand::Focus);
-                    }
-                    TrayCommand::Quit => {
-                        log::info!("Processing Quit command");
-                        self.should_exit = true;
-                    }
-                }
+                log::info!("Processing tray command {:?}", cmd);
+                let commands = handle_tray_command(cmd, &mut state);
+                self.app.pending_commands.extend(commands);
             }
+            if state.show_window {
+                ctx.send_viewport_cmd(ViewportCommand::Visible(true));
+                ctx.send_viewport_cmd(ViewportCommand::Focus);
+            }
+            self.should_exit = state.should_exit;
+            self.app.paused = state.paused;
         }
 
         let close_requested = ctx.input(|i| i.viewport().close_requested());
         if close_requested {
-            ctx.send_viewport_cmd(ViewportCommand::CancelClose);
-            ctx.send_viewport_cmd(ViewportCommand::Visible(false));
+            if self.tray_available.load(Ordering::SeqCst) {
+                ctx.send_viewport_cmd(ViewportCommand::CancelClose);
+                ctx.send_viewport_cmd(ViewportCommand::Visible(false));
+            } else if self.keep_running_hidden {
+                log::warn!(
+                    "No tray available, the window stays hidden until spacefn-rs is restarted"
+                );
+                ctx.send_viewport_cmd(ViewportCommand::CancelClose);
+                ctx.send_viewport_cmd(ViewportCommand::Visible(false));
+            } else {
+                // Without a tray a hidden window could never be shown again, so exit.
+                log::info!("No tray available, stopping on window close");
+                if let Ok(cmd_tx) = self.cmd_tx.lock() {
+                    let _ = cmd_tx.send(CoreCommand::Stop);
+                }
+            }
         }
 
         if let Ok(state_rx) = self.state_rx.lock() {
             while let Ok(msg) = state_rx.try_recv() {
                 match msg {
                     UiMessage::StateChanged(state) => self.app.update_state(state),
-                    UiMessage::KeyPressed(key) => self.app.add_key_event(key),
+                    UiMessage::Key {
+                        code,
+                        value,
+                        emitted,
+                    } => self.app.add_key_event(code, value, emitted),
                     UiMessage::Error(err) => self.app.set_error(err),
                 }
             }
         }
         self.app.update(ctx, _frame);
 
+        if let Ok(cmd_tx) = self.cmd_tx.lock() {
+            for cmd in self.app.pending_commands.drain(..) {
+                let _ = cmd_tx.send(cmd);
+            }
+        }
+        // Like Quit in the tray, the window closes on the next frame.
+        self.should_exit |= self.app.quit_requested;
+
         ctx.request_repaint_after(Duration::from_millis(100));
     }
 
@@ -484,9 +1015,31 @@ fn main() {
         }
     };
 
+    if args.diagnostics {
+        let report = diagnostics::collect(&config, &diagnostics::probe_devices(), &[]);
+        match diagnostics::write_report(&report) {
+            Ok(path) => println!(
+                "Wrote diagnostics to {}, check it before sharing as it holds your config",
+                path.display()
+            ),
+            Err(e) => log::error!("{}", e),
+        }
+        return;
+    }
+
+    let last_device = config::UiSettings::load().last_device;
     let device_path = if !config.keyboard.is_empty() {
         config.keyboard.clone()
+    } else if !last_device.is_empty() && std::path::Path::new(&last_device).exists() {
+        log::info!(
+            "No keyboard device in config, using last selected {}",
+            last_device
+        );
+        last_device
     } else {
+        if !last_device.is_empty() {
+            log::warn!("Last selected device {} is no longer present", last_device);
+        }
         log::warn!("No keyboard device specified in config");
         let devices = list_input_devices();
         if devices.is_empty() {
@@ -495,7 +1048,10 @@ fn main() {
         }
         log::info!("Available devices:");
         for (i, dev) in devices.iter().enumerate() {
-            log::info!("  {}: {} ({})", i, dev.name, dev.path);
+            match &dev.stable_path {
+                Some(stable) => log::info!("  {}: {} ({}, {})", i, dev.name, dev.path, stable),
+                None => log::info!("  {}: {} ({})", i, dev.name, dev.path),
+            }
         }
         return;
     };
@@ -505,6 +1061,14 @@ fn main() {
         return;
     }
 
+    let group_paths = config.groups.iter().map(|g| g.config.keyboard.as_str());
+    for path in std::iter::once(device_path.as_str()).chain(group_paths) {
+        if let Err(e) = check_not_virtual(path, args.force) {
+            log::error!("{}", e);
+            return;
+        }
+    }
+
     if args.cli {
         run_cli_mode(&device_path, config);
     } else {
@@ -516,3 +1080,183 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct ForwardSink {
+        sent: Vec<(u16, i32)>,
+        forwarded: Vec<(u16, i32)>,
+    }
+
+    impl KeySink for ForwardSink {
+        fn send_key(&mut self, code: u16, value: i32) -> anyhow::Result<()> {
+            self.sent.push((code, value));
+            Ok(())
+        }
+
+        fn forward_event(&mut self, event: &InputEvent) -> anyhow::Result<()> {
+            self.forwarded.push((event.code(), event.value()));
+            Ok(())
+        }
+
+        fn exec(&mut self, _command: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn key(code: u16, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY.0, code, value)
+    }
+
+    #[test]
+    fn test_mode_dispatch() {
+        let config = Config {
+            keys_map: vec![[36, 105, 0]], // J -> Left
+            ..Default::default()
+        };
+        let keys = [key(57, 1), key(36, 1), key(36, 0), key(57, 0)];
+        let now = Instant::now();
+
+        // Passthrough forwards every key as it came, Space and mapped keys alike.
+        let mut sm = StateMachine::new(config.clone());
+        let mut sink = ForwardSink::default();
+        for event in &keys {
+            let state = dispatch_key(&mut sm, Mode::Passthrough, &mut sink, event, now).unwrap();
+            assert_eq!(state, State::Idle);
+        }
+        assert_eq!(sink.forwarded, vec![(57, 1), (36, 1), (36, 0), (57, 0)]);
+        assert!(sink.sent.is_empty());
+
+        // Grab remaps the same keys.
+        let mut sm = StateMachine::new(config);
+        let mut sink = ForwardSink::default();
+        for event in &keys {
+            dispatch_key(&mut sm, Mode::Grab, &mut sink, event, now).unwrap();
+        }
+        assert!(sink.sent.contains(&(105, 1)));
+        assert!(!sink.sent.contains(&(36, 1)) && !sink.forwarded.contains(&(36, 1)));
+    }
+
+    #[test]
+    fn test_monitor_mode() {
+        // Monitor mode neither grabs nor opens a virtual device, it only reports.
+        assert!(!Mode::Monitor.grabs() && !Mode::Monitor.remaps());
+        let (state_tx, state_rx) = mpsc::channel();
+        let events = [
+            key(36, 1),
+            InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
+        ];
+        report_monitored(&events, false, &state_tx);
+        let reported: Vec<UiMessage> = state_rx.try_iter().collect();
+        assert!(matches!(
+            reported[..],
+            [UiMessage::Key {
+                code: 36,
+                value: 1,
+                emitted: false
+            }]
+        ));
+
+        report_monitored(&events, true, &state_tx);
+        assert!(state_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_sighup_reloads_config() {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        forward_signals([SIGHUP, signal_hook::consts::SIGUSR1, SIGHUP], &cmd_tx);
+        let commands: Vec<CoreCommand> = cmd_rx.try_iter().collect();
+        assert!(matches!(
+            commands[..],
+            [CoreCommand::ReloadConfig, CoreCommand::ReloadConfig]
+        ));
+    }
+
+    #[test]
+    fn test_fan_out_to_groups() {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (main_tx, main_rx) = mpsc::channel();
+        let (group_tx, group_rx) = mpsc::channel();
+        cmd_tx.send(CoreCommand::Pause).unwrap();
+        cmd_tx
+            .send(CoreCommand::SetMappings(vec![[36, 105, 0]], false))
+            .unwrap();
+        cmd_tx.send(CoreCommand::SetLocked(true)).unwrap();
+        drop(cmd_tx);
+        fan_out(cmd_rx, &main_tx, &[group_tx]);
+
+        let main: Vec<CoreCommand> = main_rx.try_iter().collect();
+        assert!(matches!(
+            main[..],
+            [
+                CoreCommand::Pause,
+                CoreCommand::SetMappings(..),
+                CoreCommand::SetLocked(true)
+            ]
+        ));
+        let group: Vec<CoreCommand> = group_rx.try_iter().collect();
+        assert!(matches!(
+            group[..],
+            [CoreCommand::Pause, CoreCommand::SetLocked(true)]
+        ));
+    }
+
+    #[test]
+    fn test_classify_select() {
+        assert_eq!(classify_select(Ok(0)).unwrap(), WaitResult::TimedOut);
+        assert_eq!(classify_select(Ok(1)).unwrap(), WaitResult::Ready);
+        assert_eq!(
+            classify_select(Err(Errno::EINTR)).unwrap(),
+            WaitResult::Interrupted
+        );
+        assert!(classify_select(Err(Errno::EBADF)).is_err());
+        assert!(classify_select(Err(Errno::ENOMEM)).is_err());
+    }
+
+    #[test]
+    fn test_tray_show_window() {
+        let mut state = TrayState::default();
+        assert!(handle_tray_command(TrayCommand::ShowWindow, &mut state).is_empty());
+        assert!(state.show_window);
+        assert!(!state.should_exit);
+    }
+
+    #[test]
+    fn test_tray_quit_stops_core() {
+        let mut state = TrayState::default();
+        let commands = handle_tray_command(TrayCommand::Quit, &mut state);
+        assert!(matches!(commands[..], [CoreCommand::Stop]));
+        assert!(state.should_exit);
+    }
+
+    #[test]
+    fn test_tray_reload_config() {
+        let mut state = TrayState::default();
+        let commands = handle_tray_command(TrayCommand::ReloadConfig, &mut state);
+        assert!(matches!(commands[..], [CoreCommand::ReloadConfig]));
+        assert_eq!(state, TrayState::default());
+    }
+
+    #[test]
+    fn test_tray_reset() {
+        let mut state = TrayState::default();
+        let commands = handle_tray_command(TrayCommand::Reset, &mut state);
+        assert!(matches!(commands[..], [CoreCommand::Reset]));
+        assert_eq!(state, TrayState::default());
+    }
+
+    #[test]
+    fn test_tray_toggle_pause() {
+        let mut state = TrayState::default();
+        let commands = handle_tray_command(TrayCommand::TogglePause, &mut state);
+        assert!(matches!(commands[..], [CoreCommand::Pause]));
+        assert!(state.paused);
+
+        let commands = handle_tray_command(TrayCommand::TogglePause, &mut state);
+        assert!(matches!(commands[..], [CoreCommand::Resume]));
+        assert!(!state.paused);
+    }
+}
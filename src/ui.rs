@@ -1,6 +1,8 @@
-use crate::core::State;
+use crate::core::{key_row, KeyValue, Row, State};
 #[cfg(feature = "ui")]
 use eframe::egui;
+use std::collections::HashMap;
+use std::time::Instant;
 
 #[cfg(feature = "ui")]
 pub use crate::{CoreCommand, UiMessage};
@@ -15,43 +17,176 @@ pub struct SpacefnApp {
     pub show_config: bool,
     pub error_message: Option<String>,
     pub new_key: (u32, u32, u32),
+    pub paused: bool,
+    pub ui_settings: crate::config::UiSettings,
+    /// Commands for the core, sent by the wrapper after each frame.
+    pub pending_commands: Vec<CoreCommand>,
+    /// Quit was clicked, the wrapper closes the window once Stop is sent.
+    pub quit_requested: bool,
+    /// How many events the Recent Keys view keeps.
+    pub history_limit: usize,
+    /// Every physical key event since recording started, oldest first.
+    pub recorded: Vec<KeyEvent>,
+    pub recording: bool,
+    /// Where the last diagnostics report was written.
+    pub diagnostics_path: Option<std::path::PathBuf>,
+    /// Recent presses per key, drawn fading out on the keyboard view.
+    pub key_flashes: HashMap<u16, KeyFlash>,
 }
 
+/// When a key was last pressed on the keyboard and last sent by spacefn.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyFlash {
+    pub physical: Option<Instant>,
+    pub emitted: Option<Instant>,
+}
+
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+const MAX_HISTORY_LIMIT: usize = 1000;
+
+/// How long a press stays visible on the keyboard view.
+const FLASH_MS: f32 = 400.0;
+
+/// Rows of the keyboard view as (code, width in key units).
+const LAYOUT: &[&[(u16, f32)]] = &[
+    &[
+        (1, 1.0),
+        (2, 1.0),
+        (3, 1.0),
+        (4, 1.0),
+        (5, 1.0),
+        (6, 1.0),
+        (7, 1.0),
+        (8, 1.0),
+        (9, 1.0),
+        (10, 1.0),
+        (11, 1.0),
+        (12, 1.0),
+        (13, 1.0),
+        (14, 2.0),
+    ],
+    &[
+        (15, 1.5),
+        (16, 1.0),
+        (17, 1.0),
+        (18, 1.0),
+        (19, 1.0),
+        (20, 1.0),
+        (21, 1.0),
+        (22, 1.0),
+        (23, 1.0),
+        (24, 1.0),
+        (25, 1.0),
+        (26, 1.0),
+        (27, 1.0),
+        (43, 1.5),
+    ],
+    &[
+        (58, 1.75),
+        (30, 1.0),
+        (31, 1.0),
+        (32, 1.0),
+        (33, 1.0),
+        (34, 1.0),
+        (35, 1.0),
+        (36, 1.0),
+        (37, 1.0),
+        (38, 1.0),
+        (39, 1.0),
+        (40, 1.0),
+        (28, 2.25),
+    ],
+    &[
+        (42, 2.25),
+        (44, 1.0),
+        (45, 1.0),
+        (46, 1.0),
+        (47, 1.0),
+        (48, 1.0),
+        (49, 1.0),
+        (50, 1.0),
+        (51, 1.0),
+        (52, 1.0),
+        (53, 1.0),
+        (54, 2.75),
+    ],
+    &[
+        (29, 1.25),
+        (125, 1.25),
+        (56, 1.25),
+        (57, 6.25),
+        (100, 1.25),
+        (126, 1.25),
+        (127, 1.25),
+        (97, 1.25),
+    ],
+    &[
+        (102, 1.0),
+        (107, 1.0),
+        (104, 1.0),
+        (109, 1.0),
+        (110, 1.0),
+        (111, 1.0),
+        (105, 1.0),
+        (103, 1.0),
+        (108, 1.0),
+        (106, 1.0),
+    ],
+];
+
 #[derive(Clone, Debug)]
 pub struct KeyEvent {
     pub code: u16,
-    pub value: KeyValue,
+    /// The raw EV_KEY value, see `KeyValue::parse`.
+    pub value: i32,
     pub timestamp: std::time::Instant,
 }
 
-#[derive(Clone, Debug, Copy)]
-pub enum KeyValue {
-    Release,
-    Press,
-    Repeat,
-}
-
 impl KeyEvent {
     pub fn new(code: u16, value: i32) -> Self {
         Self {
             code,
-            value: match value {
-                0 => KeyValue::Release,
-                1 => KeyValue::Press,
-                2 => KeyValue::Repeat,
-                _ => KeyValue::Press,
-            },
+            value,
             timestamp: std::time::Instant::now(),
         }
     }
 
     pub fn display_string(&self) -> String {
-        let value_str = match self.value {
-            KeyValue::Press => "↓",
-            KeyValue::Release => "↑",
-            KeyValue::Repeat => "↻",
+        let value_str = match KeyValue::parse(self.value) {
+            Some(KeyValue::Press) => "↓".to_string(),
+            Some(KeyValue::Release) => "↑".to_string(),
+            Some(KeyValue::Repeat) => "↻".to_string(),
+            None => format!("?{}", self.value),
         };
-        format!("{} {:03} {}", value_str, self.code, get_key_name(self.code))
+        format!("{} {:03} {}", value_str, self.code, key_label(self.code))
+    }
+
+    /// The event as evtest prints it, e.g. `type 1 (EV_KEY), code 36 (KEY_J), value 1`.
+    pub fn raw_string(&self) -> String {
+        format!(
+            "type 1 (EV_KEY), code {} ({}), value {}",
+            self.code,
+            evdev_key_name(self.code).unwrap_or_else(|| "?".to_string()),
+            self.value
+        )
+    }
+}
+
+/// The kernel's name for a key, e.g. `KEY_J`, if evdev knows it.
+fn evdev_key_name(code: u16) -> Option<String> {
+    let name = format!("{:?}", evdev::KeyCode::new(code));
+    (!name.starts_with("unknown")).then_some(name)
+}
+
+/// The short name of a key, or for keys missing from that table its evdev name and hex code.
+fn key_label(code: u16) -> String {
+    match get_key_name(code) {
+        "?" => format!(
+            "{} (0x{:03x}, no short name)",
+            evdev_key_name(code).unwrap_or_else(|| "unknown key".to_string()),
+            code
+        ),
+        short => short.to_string(),
     }
 }
 
@@ -183,6 +318,14 @@ pub fn get_key_name(code: u16) -> &'static str {
     }
 }
 
+pub fn state_label(state: State) -> &'static str {
+    match state {
+        State::Idle => "IDLE",
+        State::Decide => "DECIDE",
+        State::Shift => "FN MODE",
+    }
+}
+
 impl SpacefnApp {
     pub fn new() -> Self {
         Self {
@@ -194,6 +337,15 @@ impl SpacefnApp {
             show_config: false,
             error_message: None,
             new_key: (0, 0, 0),
+            paused: false,
+            ui_settings: crate::config::UiSettings::default(),
+            pending_commands: Vec::new(),
+            quit_requested: false,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            recorded: Vec::new(),
+            recording: false,
+            diagnostics_path: None,
+            key_flashes: HashMap::new(),
         }
     }
 
@@ -201,16 +353,28 @@ impl SpacefnApp {
         self.current_state = state;
     }
 
-    pub fn add_key_event(&mut self, code: u16) {
-        self.add_key_event_full(code, 1);
+    /// Records a key event read from the keyboard, or sent by spacefn when `emitted`.
+    pub fn add_key_event(&mut self, code: u16, value: i32, emitted: bool) {
+        if value == 1 {
+            let flash = self.key_flashes.entry(code).or_default();
+            if emitted {
+                flash.emitted = Some(Instant::now());
+            } else {
+                flash.physical = Some(Instant::now());
+            }
+        }
+        if !emitted {
+            self.add_key_event_full(code, value);
+        }
     }
 
     pub fn add_key_event_full(&mut self, code: u16, value: i32) {
         let event = KeyEvent::new(code, value);
-        self.key_history.insert(0, event);
-        if self.key_history.len() > 20 {
-            self.key_history.pop();
+        if self.recording {
+            self.recorded.push(event.clone());
         }
+        self.key_history.insert(0, event);
+        self.key_history.truncate(self.history_limit);
     }
 
     pub fn set_error(&mut self, error: String) {
@@ -221,8 +385,10 @@ impl SpacefnApp {
         self.error_message = None;
     }
 
+    /// Loads the config without this machine's overlays, so Save never writes them into
+    /// the shared file. Apply merges them back in.
     pub fn reload_config(&mut self) {
-        match crate::config::Config::load() {
+        match crate::config::Config::load_base() {
             Ok(config) => {
                 self.config = config;
                 self.clear_error();
@@ -233,6 +399,73 @@ impl SpacefnApp {
         }
     }
 
+    /// Writes a diagnostics report with the recent keys and shows where it went.
+    pub fn generate_diagnostics(&mut self) {
+        let key_events: Vec<String> = self
+            .key_history
+            .iter()
+            .map(|e| e.display_string())
+            .collect();
+        let report = crate::diagnostics::collect(
+            &self.config,
+            &crate::diagnostics::probe_devices(),
+            &key_events,
+        );
+        match crate::diagnostics::write_report(&report) {
+            Ok(path) => {
+                self.diagnostics_path = Some(path);
+                self.clear_error();
+            }
+            Err(e) => self.set_error(e.to_string()),
+        }
+    }
+
+    /// Copies the in-memory config to the clipboard and prints it to stdout.
+    pub fn export_config(&mut self, ctx: &egui::Context) {
+        match self.config.to_toml_string() {
+            Ok(content) => {
+                println!("{}", content);
+                ctx.output_mut(|o| o.copied_text = content);
+                self.clear_error();
+            }
+            Err(e) => self.set_error(format!("Failed to export config: {}", e)),
+        }
+    }
+
+    /// Starts a new recording, dropping the previous one, or stops the current one.
+    pub fn toggle_recording(&mut self) {
+        self.recording = !self.recording;
+        if self.recording {
+            self.recorded.clear();
+        }
+    }
+
+    /// A key event for Recent Keys and recordings, with its raw values if they are shown.
+    fn event_line(&self, event: &KeyEvent) -> String {
+        if self.ui_settings.raw_values {
+            format!("{}    {}", event.display_string(), event.raw_string())
+        } else {
+            event.display_string()
+        }
+    }
+
+    /// Copies the recorded events, timed from the first, to the clipboard and stdout.
+    pub fn export_recording(&mut self, ctx: &egui::Context) {
+        let Some(start) = self.recorded.first().map(|e| e.timestamp) else {
+            return;
+        };
+        let content: String = self
+            .recorded
+            .iter()
+            .map(|e| {
+                let ms = e.timestamp.duration_since(start).as_millis();
+                format!("+{:>7} ms {}\n", ms, self.event_line(e))
+            })
+            .collect();
+        println!("{}", content);
+        ctx.output_mut(|o| o.copied_text = content);
+    }
+
     fn state_color(&self) -> egui::Color32 {
         match self.current_state {
             State::Idle => egui::Color32::from_rgb(76, 175, 80),
@@ -242,11 +475,7 @@ impl SpacefnApp {
     }
 
     fn state_text(&self) -> &'static str {
-        match self.current_state {
-            State::Idle => "IDLE",
-            State::Decide => "DECIDE",
-            State::Shift => "FN MODE",
-        }
+        state_label(self.current_state)
     }
 }
 
@@ -276,9 +505,20 @@ impl eframe::App for SpacefnApp {
                     self.show_config = true;
                 }
 
+                let pause_label = if self.paused { "Resume" } else { "Pause" };
+                if ui.button(pause_label).clicked() {
+                    self.paused = !self.paused;
+                    self.pending_commands.push(if self.paused {
+                        CoreCommand::Pause
+                    } else {
+                        CoreCommand::Resume
+                    });
+                }
+
                 ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                     if ui.button("Quit").clicked() {
-                        std::process::exit(0);
+                        self.pending_commands.push(CoreCommand::Stop);
+                        self.quit_requested = true;
                     }
                 });
             });
@@ -317,12 +557,66 @@ impl SpacefnApp {
         ui.label(format!("Mappings: {} keys", self.config.keys_map.len()));
 
         ui.separator();
-        ui.label("Recent Keys");
-        ui.separator();
+        self.show_keyboard(ui);
 
-        for event in &self.key_history {
-            ui.label(event.display_string());
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Recent Keys");
+            ui.add(
+                egui::DragValue::new(&mut self.history_limit)
+                    .clamp_range(1..=MAX_HISTORY_LIMIT)
+                    .prefix("limit: "),
+            );
+            let record_label = if self.recording {
+                "Stop Recording"
+            } else {
+                "Start Recording"
+            };
+            if ui.button(record_label).clicked() {
+                self.toggle_recording();
+            }
+            if ui
+                .checkbox(&mut self.ui_settings.raw_values, "Raw values")
+                .on_hover_text("Show type, code and value as evtest does")
+                .changed()
+            {
+                if let Err(e) = self.ui_settings.save() {
+                    self.set_error(format!("Failed to save UI settings: {}", e));
+                }
+            }
+            if ui
+                .add_enabled(
+                    !self.recorded.is_empty(),
+                    egui::Button::new("Export Recording"),
+                )
+                .clicked()
+            {
+                self.export_recording(ui.ctx());
+            }
+        });
+        if self.recording || !self.recorded.is_empty() {
+            let status = if self.recording {
+                "Recording"
+            } else {
+                "Recorded"
+            };
+            ui.colored_label(
+                egui::Color32::from_rgb(244, 67, 54),
+                format!("{}: {} events", status, self.recorded.len()),
+            );
         }
+        ui.separator();
+
+        // Lowering the limit takes effect at once rather than at the next key.
+        self.key_history.truncate(self.history_limit);
+        egui::ScrollArea::vertical()
+            .id_source("recent_keys")
+            .max_height(300.0)
+            .show(ui, |ui| {
+                for event in &self.key_history {
+                    ui.label(self.event_line(event));
+                }
+            });
 
         if self.key_history.is_empty() {
             ui.colored_label(egui::Color32::GRAY, "No key events");
@@ -334,7 +628,76 @@ impl SpacefnApp {
         }
     }
 
+    /// Draws the keyboard, flashing keys pressed (blue) and sent (orange outline).
+    fn show_keyboard(&mut self, ui: &mut egui::Ui) {
+        let now = Instant::now();
+        let fade = |at: Option<Instant>| {
+            at.map(|at| 1.0 - now.duration_since(at).as_millis() as f32 / FLASH_MS)
+                .filter(|&alpha| alpha > 0.0)
+        };
+        self.key_flashes
+            .retain(|_, flash| fade(flash.physical).is_some() || fade(flash.emitted).is_some());
+
+        let unit = (ui.available_width() / 15.0).clamp(16.0, 40.0);
+        let (rect, _) = ui.allocate_exact_size(
+            egui::vec2(unit * 15.0, unit * LAYOUT.len() as f32),
+            egui::Sense::hover(),
+        );
+        let painter = ui.painter_at(rect);
+        let font = egui::FontId::proportional(unit * 0.3);
+
+        for (row, keys) in LAYOUT.iter().enumerate() {
+            let mut x = rect.left();
+            let y = rect.top() + row as f32 * unit;
+            for &(code, width) in keys.iter() {
+                let key =
+                    egui::Rect::from_min_size(egui::pos2(x, y), egui::vec2(width * unit, unit))
+                        .shrink(1.5);
+                x += width * unit;
+
+                let flash = self.key_flashes.get(&code).copied().unwrap_or_default();
+                let mut fill = egui::Color32::from_gray(60);
+                if let Some(alpha) = fade(flash.physical) {
+                    fill = egui::Color32::from_rgb(60, 120, 220).gamma_multiply(alpha);
+                }
+                painter.rect_filled(key, 3.0, fill);
+                if let Some(alpha) = fade(flash.emitted) {
+                    let color = egui::Color32::from_rgb(240, 150, 40).gamma_multiply(alpha);
+                    painter.rect_stroke(key, 3.0, egui::Stroke::new(2.5, color));
+                }
+                painter.text(
+                    key.center(),
+                    egui::Align2::CENTER_CENTER,
+                    get_key_name(code),
+                    font.clone(),
+                    egui::Color32::WHITE,
+                );
+            }
+        }
+
+        if !self.key_flashes.is_empty() {
+            ui.ctx().request_repaint();
+        }
+    }
+
     fn show_config_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label("Interface");
+        ui.separator();
+
+        let response = ui.add(
+            egui::Slider::new(&mut self.ui_settings.scale, 0.5..=3.0)
+                .step_by(0.25)
+                .text("UI scale"),
+        );
+        // Rescaling mid-drag moves the slider under the pointer, wait for the release.
+        if response.drag_released() || (response.changed() && !response.dragged()) {
+            ui.ctx().set_zoom_factor(self.ui_settings.scale);
+            if let Err(e) = self.ui_settings.save() {
+                self.set_error(format!("Failed to save UI settings: {}", e));
+            }
+        }
+
+        ui.separator();
         ui.label("Keyboard Device");
         ui.separator();
 
@@ -354,7 +717,14 @@ impl SpacefnApp {
 
         if let Some(idx) = self.selected_device {
             if ui.button("Use This Device").clicked() {
-                self.config.keyboard = self.devices[idx].path.clone();
+                // A stable link keeps the config working when event numbers change.
+                let device = &self.devices[idx];
+                self.config.keyboard = device.stable_path.clone().unwrap_or(device.path.clone());
+                // Remembered even if the config is never saved.
+                self.ui_settings.last_device = self.config.keyboard.clone();
+                if let Err(e) = self.ui_settings.save() {
+                    self.set_error(format!("Failed to save UI settings: {}", e));
+                }
             }
         }
 
@@ -364,28 +734,41 @@ impl SpacefnApp {
 
         let mut to_remove: Vec<usize> = Vec::new();
 
-        for (i, mapping) in self.config.keys_map.iter().enumerate() {
-            ui.horizontal(|ui| {
-                let orig = get_key_name(mapping[0] as u16);
-                let mapped = if mapping[1] == 0 {
-                    "orig".to_string()
-                } else {
-                    get_key_name(mapping[1] as u16).to_string()
-                };
-                let ext = if mapping[2] == 0 {
-                    "-".to_string()
-                } else {
-                    get_key_name(mapping[2] as u16).to_string()
-                };
-
-                ui.label(format!("{} -> {} [{}]", orig, mapped, ext));
-
-                if ui.button("X").clicked() {
-                    to_remove.push(i);
-                }
-            });
+        for row in Row::ALL {
+            let in_row: Vec<usize> = (0..self.config.keys_map.len())
+                .filter(|&i| key_row(self.config.keys_map[i][0] as u16) == row)
+                .collect();
+            egui::CollapsingHeader::new(format!("{} ({})", row.label(), in_row.len()))
+                .id_source(row.label())
+                .default_open(true)
+                .show(ui, |ui| {
+                    for i in in_row {
+                        let mapping = self.config.keys_map[i];
+                        ui.horizontal(|ui| {
+                            let orig = get_key_name(mapping[0] as u16);
+                            let mapped = if mapping[1] == 0 {
+                                "orig".to_string()
+                            } else {
+                                get_key_name(mapping[1] as u16).to_string()
+                            };
+                            let ext = if mapping[2] == 0 {
+                                "-".to_string()
+                            } else {
+                                get_key_name(mapping[2] as u16).to_string()
+                            };
+
+                            ui.label(format!("{} -> {} [{}]", orig, mapped, ext));
+
+                            if ui.button("X").clicked() {
+                                to_remove.push(i);
+                            }
+                        });
+                    }
+                });
         }
 
+        // Rows list mappings out of order, remove from the back.
+        to_remove.sort_unstable();
         for i in to_remove.iter().rev() {
             self.config.keys_map.remove(*i);
         }
@@ -396,17 +779,17 @@ impl SpacefnApp {
             ui.label("Add:");
             ui.add(
                 egui::DragValue::new(&mut self.new_key.0)
-                    .clamp_range(0..=255)
+                    .clamp_range(0..=crate::core::KEY_MAX)
                     .speed(1.0),
             );
             ui.add(
                 egui::DragValue::new(&mut self.new_key.1)
-                    .clamp_range(0..=255)
+                    .clamp_range(0..=crate::core::KEY_MAX)
                     .speed(1.0),
             );
             ui.add(
                 egui::DragValue::new(&mut self.new_key.2)
-                    .clamp_range(0..=255)
+                    .clamp_range(0..=crate::core::KEY_MAX)
                     .speed(1.0),
             );
             if ui.button("Add").clicked() {
@@ -414,6 +797,13 @@ impl SpacefnApp {
                     .keys_map
                     .push([self.new_key.0, self.new_key.1, self.new_key.2]);
             }
+            if ui
+                .button("Numpad Preset")
+                .on_hover_text("Space+UIO/JKL/M,./N as a numpad, with NumLock turned on for it")
+                .clicked()
+            {
+                self.config.apply_numpad_preset();
+            }
         });
 
         ui.separator();
@@ -424,16 +814,61 @@ impl SpacefnApp {
                     let path = home.join(".config/spacefn/config.toml");
                     match self.config.save(&path) {
                         Ok(_) => self.clear_error(),
-                        Err(e) => self.set_error(e.to_string()),
+                        Err(e) => self.set_error(format!("{} (use Export to keep your config)", e)),
                     }
+                } else {
+                    self.set_error(
+                        "No home directory found, use Export to keep your config".to_string(),
+                    );
                 }
             }
+            if ui
+                .button("Apply")
+                .on_hover_text("Use these mappings now without saving")
+                .clicked()
+            {
+                let mut applied = self.config.clone();
+                match applied.apply_overlays() {
+                    Ok(()) => self.pending_commands.push(CoreCommand::SetMappings(
+                        applied.keys_map,
+                        applied.numpad_numlock,
+                    )),
+                    Err(e) => self.set_error(format!("{:#}", e)),
+                }
+            }
+            if ui.button("Export").clicked() {
+                self.export_config(ui.ctx());
+            }
             if ui.button("Reload").clicked() {
                 self.reload_config();
+                // Drops mappings that were applied but never saved.
+                self.pending_commands.push(CoreCommand::ReloadConfig);
+            }
+            if ui
+                .button("Reset Keys")
+                .on_hover_text("Release every key held by spacefn")
+                .clicked()
+            {
+                self.pending_commands.push(CoreCommand::Reset);
             }
             if ui.button("Refresh").clicked() {
                 self.devices = crate::core::list_input_devices();
             }
+            if ui
+                .button("Generate Diagnostics")
+                .on_hover_text(
+                    "Write a report to attach to a bug report, including your recent keys",
+                )
+                .clicked()
+            {
+                self.generate_diagnostics();
+            }
         });
+        if let Some(path) = &self.diagnostics_path {
+            ui.label(format!(
+                "Diagnostics written to {}. It lists the keys you typed recently, check it before sharing.",
+                path.display()
+            ));
+        }
     }
 }